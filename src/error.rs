@@ -0,0 +1,81 @@
+use std::{fmt, io};
+
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum ConverseError {
+    // The request could not be sent, or the response could not be read.
+    Transport(reqwest::Error),
+    // The provider answered with an error status.
+    Status {
+        status: StatusCode,
+        message: Option<String>,
+    },
+    // The provider reported an error in the middle of the answer.
+    Provider(String),
+    // The response was not in the format the provider is expected to use.
+    Decode(String),
+    // A config value that can't be used.
+    Config(String),
+    Io(io::Error),
+}
+
+impl ConverseError {
+    // Short heading shown above the error in the chat.
+    pub fn title(&self) -> String {
+        match self {
+            Self::Transport(error) if error.is_connect() => {
+                "Could not connect to a server".to_string()
+            }
+            Self::Transport(error) if error.is_timeout() => "The request timed out".to_string(),
+            Self::Transport(_) => "Network error".to_string(),
+            Self::Status { status, .. } => format!("The provider returned an error ({})", status),
+            Self::Provider(_) => "The provider stopped with an error".to_string(),
+            Self::Decode(_) => "Unexpected response".to_string(),
+            Self::Config(_) => "Configuration error".to_string(),
+            Self::Io(_) => "Could not access the history".to_string(),
+        }
+    }
+
+    // Config errors need the user to edit config.toml, so sending again won't help.
+    pub fn can_retry(&self) -> bool {
+        !matches!(self, Self::Config(_))
+    }
+}
+
+impl fmt::Display for ConverseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(error) => write!(f, "{}", error),
+            Self::Status {
+                message: Some(message),
+                ..
+            } => write!(f, "{}", message),
+            Self::Status { status, .. } => write!(f, "{}", status),
+            Self::Provider(message) | Self::Decode(message) | Self::Config(message) => {
+                write!(f, "{}", message)
+            }
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ConverseError {}
+
+impl From<reqwest::Error> for ConverseError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Transport(error)
+    }
+}
+
+impl From<serde_json::Error> for ConverseError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Decode(error.to_string())
+    }
+}
+
+impl From<io::Error> for ConverseError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
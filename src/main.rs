@@ -1,12 +1,27 @@
+mod error;
 mod models;
 mod parser;
-
-use models::get_models;
+mod usage;
+
+use error::ConverseError;
+use models::{
+    get_models,
+    tools::{
+        mcp::{self, McpStatus},
+        shell::{self, CommandRequest},
+        ToolCall, ToolResult, ToolRound,
+    },
+    ChatContent, Progress, Selection, Source, Usage,
+};
 use parser::{
     cache::Cache,
-    config::Config,
+    config::{Args, Command, Config, ConfigFiles, ConfigGeneration},
+    files::{self, ContextFile, FileSource},
     md2pango::md2pango,
+    models_cache::{ModelFlag, ModelsCache},
+    time,
 };
+use usage::{format_cost, DayTotal};
 
 use gdk::{gio, keys::constants as keys, ModifierType};
 use gtk::{
@@ -14,39 +29,433 @@ use gtk::{
     prelude::*,
     Application, ApplicationWindow, Button, ComboBoxText, Entry, Label, ListStore, ScrolledWindow,
 };
+use clap::Parser;
 use gtk_layer_shell::{Edge, Layer, LayerShell};
-use serde_json::json;
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     fs,
     path::PathBuf,
     rc::Rc,
+    str::FromStr,
     sync::{Arc, OnceLock},
+    time::{Duration, Instant},
     usize,
 };
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, sync::Notify};
 
 fn runtime() -> &'static Runtime {
     static RUNTIME: OnceLock<Runtime> = OnceLock::new();
     RUNTIME.get_or_init(|| Runtime::new().expect("Setting up tokio runtime needs to succeed."))
 }
 
+// Attached directories can be large, so they're read on tokio's blocking threads.
+async fn read_files(sources: Vec<FileSource>, limits: ConfigFiles) -> Vec<ContextFile> {
+    runtime()
+        .spawn_blocking(move || files::read(&sources, &limits))
+        .await
+        .unwrap_or_default()
+}
+
+// Shortens large token counts, e.g. 12345 -> 12.3k.
+fn format_tokens(tokens: u64) -> String {
+    match tokens {
+        0..=999 => tokens.to_string(),
+        1_000..=999_999 => format!("{:.1}k", tokens as f64 / 1_000.0),
+        _ => format!("{:.1}M", tokens as f64 / 1_000_000.0),
+    }
+}
+
 #[derive(Clone)]
 struct Tabs {
     tab: gtk::Box,
     id: usize,
     file: PathBuf,
-    model: Option<String>,
+    model: Option<Selection>,
+    pending: Option<Pending>,
+    // Override the provider's system prompt and sampling parameters for this conversation.
+    system_prompt: String,
+    generation: ConfigGeneration,
+    usage: Usage,
+    // Only set once an answer from a priced model is added.
+    cost: Option<f64>,
+    usage_label: Label,
 }
 
-impl Tabs {
-    fn get_tab_from_id(id: usize, tabs: &Vec<Tabs>) -> Option<gtk::Box> {
-        for tab in tabs {
-            if tab.id == id {
-                return Some(tab.tab.clone());
+// A model answer that is still being streamed in.
+#[derive(Clone)]
+struct Pending {
+    question_box: gtk::Box,
+    // Holds a bubble for each tool the model called, between the question and the answer.
+    tools_box: gtk::Box,
+    answer_box: gtk::Box,
+    // Kept with its attachments, so it can be sent again.
+    question: ChatContent,
+    text: String,
+    cancel: Arc<Notify>,
+    // Shows the reasoning streamed so far, once there is any.
+    thinking: Option<Label>,
+    // The answer being continued, which the text is appended to. Continuations have
+    // no question of their own.
+    previous: Option<ChatContent>,
+}
+
+// Sent from the request task to the UI.
+enum Update {
+    Chunk(String),
+    Thinking(String),
+    Retrying(Duration),
+    ToolCall(ToolCall),
+    ToolResult(ToolResult),
+    // The attached files, read once the question was sent.
+    Context(Vec<ContextFile>),
+    // Boxed, as a whole answer is much larger than the other updates.
+    Done(Box<Result<ChatContent, ConverseError>>),
+    Cancelled,
+}
+
+impl From<Progress<'_>> for Update {
+    fn from(progress: Progress) -> Self {
+        match progress {
+            Progress::Chunk(chunk) => Update::Chunk(chunk.to_string()),
+            Progress::Thinking(thinking) => Update::Thinking(thinking.to_string()),
+            Progress::Retrying(delay) => Update::Retrying(delay),
+            Progress::ToolCall(call) => Update::ToolCall(call.clone()),
+            Progress::ToolResult(result) => Update::ToolResult(result.clone()),
+        }
+    }
+}
+
+// Popover used to edit the system prompt and sampling parameters of a
+// conversation. Empty fields use the values from the provider's config section.
+#[derive(Clone)]
+struct ConversationSettings {
+    popover: gtk::Popover,
+    system_prompt: gtk::TextView,
+    temperature: Entry,
+    top_p: Entry,
+    max_output_tokens: Entry,
+    stop: Entry,
+    seed: Entry,
+    apply_button: Button,
+}
+
+impl ConversationSettings {
+    fn new(relative_to: &gtk::MenuButton) -> Self {
+        let system_prompt_label = Label::new(Some("System prompt"));
+        system_prompt_label.set_halign(gtk::Align::Start);
+        let system_prompt = gtk::TextView::builder()
+            .wrap_mode(gtk::WrapMode::WordChar)
+            .accepts_tab(false)
+            .build();
+        let system_prompt_scroll = ScrolledWindow::builder()
+            .min_content_height(80)
+            .min_content_width(300)
+            .build();
+        system_prompt_scroll.add(&system_prompt);
+
+        let grid = gtk::Grid::builder().row_spacing(5).column_spacing(10).build();
+        let new_entry = |row: i32, name: &str| {
+            let label = Label::new(Some(name));
+            label.set_halign(gtk::Align::Start);
+            let entry = Entry::new();
+            grid.attach(&label, 0, row, 1, 1);
+            grid.attach(&entry, 1, row, 1, 1);
+            entry
+        };
+        let temperature = new_entry(0, "Temperature");
+        let top_p = new_entry(1, "Top P");
+        let max_output_tokens = new_entry(2, "Max output tokens");
+        let stop = new_entry(3, "Stop sequences");
+        stop.set_tooltip_text(Some("Separated by commas"));
+        let seed = new_entry(4, "Seed");
+
+        let reset_button = Button::with_label("Reset");
+        let apply_button = Button::with_label("Apply");
+        let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+        buttons.set_halign(gtk::Align::End);
+        buttons.pack_start(&reset_button, false, false, 0);
+        buttons.pack_start(&apply_button, false, false, 0);
+
+        let layout = gtk::Box::new(gtk::Orientation::Vertical, 10);
+        layout.style_context().add_class("conversation-settings");
+        layout.pack_start(&system_prompt_label, false, false, 0);
+        layout.pack_start(&system_prompt_scroll, true, true, 0);
+        layout.pack_start(&grid, false, false, 0);
+        layout.pack_start(&buttons, false, false, 0);
+        layout.show_all();
+
+        let popover = gtk::Popover::new(Some(relative_to));
+        popover.add(&layout);
+
+        let settings = Self {
+            popover,
+            system_prompt,
+            temperature,
+            top_p,
+            max_output_tokens,
+            stop,
+            seed,
+            apply_button,
+        };
+        reset_button.connect_clicked(clone!(@strong settings => move |_| {
+            settings.system_prompt.buffer().unwrap().set_text("");
+            for entry in settings.entries() {
+                entry.set_text("");
+            }
+        }));
+        settings
+    }
+
+    fn entries(&self) -> [&Entry; 5] {
+        [&self.temperature, &self.top_p, &self.max_output_tokens, &self.stop, &self.seed]
+    }
+
+    // Shows the conversation's values, with the provider's ones as placeholders.
+    fn set(
+        &self,
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        default_prompt: &str,
+        defaults: &ConfigGeneration,
+    ) {
+        self.system_prompt.buffer().unwrap().set_text(system_prompt);
+        self.system_prompt.set_tooltip_text(if default_prompt.is_empty() {
+            None
+        } else {
+            Some(default_prompt)
+        });
+
+        fn text<T: ToString>(value: Option<T>) -> String {
+            value.map(|value| value.to_string()).unwrap_or_default()
+        }
+        let values = [
+            (text(generation.temperature), text(defaults.temperature)),
+            (text(generation.top_p), text(defaults.top_p)),
+            (text(generation.max_output_tokens), text(defaults.max_output_tokens)),
+            (generation.stop.join(", "), defaults.stop.join(", ")),
+            (text(generation.seed), text(defaults.seed)),
+        ];
+        for (entry, (value, default)) in self.entries().into_iter().zip(values) {
+            entry.set_text(&value);
+            entry.set_placeholder_text(Some(if default.is_empty() { "Default" } else { &default }));
+            entry.style_context().remove_class("error");
+        }
+    }
+
+    // None if a field can't be parsed, in which case it is marked as invalid.
+    fn get(&self) -> Option<(String, ConfigGeneration)> {
+        fn parse<T: FromStr>(entry: &Entry) -> Option<Option<T>> {
+            let text = entry.text();
+            let value = if text.trim().is_empty() {
+                Some(None)
+            } else {
+                text.trim().parse().ok().map(Some)
+            };
+            if value.is_some() {
+                entry.style_context().remove_class("error");
+            } else {
+                entry.style_context().add_class("error");
             }
+            value
         }
-        None
+        let temperature = parse(&self.temperature);
+        let top_p = parse(&self.top_p);
+        let max_output_tokens = parse(&self.max_output_tokens);
+        let seed = parse(&self.seed);
+        let stop = self
+            .stop
+            .text()
+            .split(',')
+            .map(str::trim)
+            .filter(|stop| !stop.is_empty())
+            .map(str::to_string)
+            .collect();
+        let buffer = self.system_prompt.buffer().unwrap();
+        let (start, end) = buffer.bounds();
+        let system_prompt = buffer
+            .text(&start, &end, false)
+            .map(|text| text.trim().to_string())
+            .unwrap_or_default();
+        let generation = ConfigGeneration {
+            temperature: temperature?,
+            top_p: top_p?,
+            max_output_tokens: max_output_tokens?,
+            stop,
+            seed: seed?,
+        };
+        Some((system_prompt, generation))
+    }
+}
+
+impl Tabs {
+    fn get_tab_from_id(id: usize, tabs: &mut [Tabs]) -> Option<&mut Tabs> {
+        tabs.iter_mut().find(|tab| tab.id == id)
+    }
+
+    // Stops any running request before deleting the history, so it isn't written again.
+    fn close(&self) {
+        if let Some(pending) = &self.pending {
+            pending.cancel.notify_one();
+        }
+        Cache::delete(&self.file).ok();
+    }
+
+    // Adds an answer's tokens to the running total shown in the tab header.
+    fn add_usage(&mut self, usage: Usage, cost: Option<f64>) {
+        self.usage += usage;
+        if let Some(cost) = cost {
+            *self.cost.get_or_insert(0.0) += cost;
+        }
+        let mut text = format!("{} tokens", format_tokens(self.usage.total()));
+        if let Some(cost) = self.cost {
+            text.push_str(&format!(" · {}", format_cost(cost)));
+        }
+        self.usage_label.set_text(&text);
+        self.usage_label.set_tooltip_text(Some(&usage_tooltip(&self.usage)));
+    }
+}
+
+// Cache counts are only listed when the provider cached part of the prompt.
+fn usage_tooltip(usage: &Usage) -> String {
+    let mut text = format!(
+        "Input: {} tokens\nOutput: {} tokens",
+        usage.input_tokens, usage.output_tokens
+    );
+    if usage.cache_creation_tokens > 0 || usage.cache_read_tokens > 0 {
+        text.push_str(&format!(
+            "\nWritten to cache: {} tokens\nRead from cache: {} tokens",
+            usage.cache_creation_tokens, usage.cache_read_tokens
+        ));
+    }
+    text
+}
+
+// Images and files attached to the next question, shown above the entry.
+// Images are saved to the history's attachments directory as soon as they are added,
+// while files are read when the question is sent.
+#[derive(Clone)]
+struct Attachments {
+    strip: gtk::Box,
+    history_path: PathBuf,
+    names: Rc<RefCell<Vec<String>>>,
+    sources: Rc<RefCell<Vec<FileSource>>>,
+}
+
+impl Attachments {
+    fn new(history_path: PathBuf) -> Self {
+        let strip = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+        strip.style_context().add_class("attachments");
+        Self {
+            strip,
+            history_path,
+            names: Rc::new(RefCell::new(Vec::new())),
+            sources: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    // PNG and JPEG files are attached as images, anything else as text.
+    fn add_file(&self, path: &std::path::Path) {
+        if path.is_dir() {
+            self.add_source(FileSource {
+                path: path.to_path_buf(),
+                pattern: String::new(),
+            });
+            return;
+        }
+        match fs::read(path) {
+            Ok(bytes) if models::Image::mime_type_of(&bytes).is_some() => self.add(&bytes),
+            Ok(_) => self.add_source(FileSource {
+                path: path.to_path_buf(),
+                pattern: String::new(),
+            }),
+            Err(error) => eprintln!("Couldn't read {}: {}", path.display(), error),
+        }
+    }
+
+    // Directories get an entry for the glob that picks the files to read.
+    fn add_source(&self, source: FileSource) {
+        let item = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        item.style_context().add_class("attached-source");
+        let remove_image =
+            gtk::Image::from_icon_name(Some("window-close-symbolic"), gtk::IconSize::Button);
+        let remove_button = Button::builder()
+            .image(&remove_image)
+            .relief(gtk::ReliefStyle::None)
+            .halign(gtk::Align::End)
+            .build();
+        let name = source
+            .path
+            .file_name()
+            .map_or(source.path.display().to_string(), |name| name.to_string_lossy().to_string());
+        let label = Label::new(Some(&name));
+        label.set_tooltip_text(Some(&source.path.display().to_string()));
+        item.pack_start(&remove_button, false, false, 0);
+        item.pack_start(&label, false, false, 0);
+        if source.path.is_dir() {
+            let pattern_entry = Entry::builder()
+                .text(&source.pattern)
+                .placeholder_text("All files")
+                .tooltip_text("Files to read, e.g. *.rs, *.toml")
+                .width_chars(10)
+                .build();
+            let sources = self.sources.clone();
+            let path = source.path.clone();
+            pattern_entry.connect_changed(move |pattern_entry| {
+                for source in sources.borrow_mut().iter_mut().filter(|source| source.path == path) {
+                    source.pattern = pattern_entry.text().to_string();
+                }
+            });
+            item.pack_start(&pattern_entry, false, false, 0);
+        }
+        self.strip.pack_start(&item, false, false, 0);
+        self.strip.show_all();
+
+        let sources = self.sources.clone();
+        let path = source.path.clone();
+        self.sources.borrow_mut().push(source);
+        remove_button.connect_clicked(clone!(@weak item, @weak self.strip as strip => move |_| {
+            sources.borrow_mut().retain(|source| source.path != path);
+            strip.remove(&item);
+        }));
+    }
+
+    fn add(&self, bytes: &[u8]) {
+        match Cache::save_image(&self.history_path, bytes) {
+            Ok(name) => self.add_saved(name),
+            Err(error) => eprintln!("{}: {}", error.title(), error),
+        }
+    }
+
+    fn add_saved(&self, name: String) {
+        let item = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let remove_image =
+            gtk::Image::from_icon_name(Some("window-close-symbolic"), gtk::IconSize::Button);
+        let remove_button = Button::builder()
+            .image(&remove_image)
+            .relief(gtk::ReliefStyle::None)
+            .halign(gtk::Align::End)
+            .build();
+        item.pack_start(&remove_button, false, false, 0);
+        item.pack_start(&UI::thumbnail(&self.history_path, &name), false, false, 0);
+        self.strip.pack_start(&item, false, false, 0);
+        self.strip.show_all();
+
+        let names = self.names.clone();
+        let file = Cache::attachments_dir(&self.history_path).join(&name);
+        self.names.borrow_mut().push(name.clone());
+        remove_button.connect_clicked(clone!(@weak item, @weak self.strip as strip => move |_| {
+            names.borrow_mut().retain(|attached| attached != &name);
+            strip.remove(&item);
+            fs::remove_file(&file).ok();
+        }));
+    }
+
+    // Empties the strip, returning the names of the images and the files it held.
+    fn take(&self) -> (Vec<String>, Vec<FileSource>) {
+        for child in self.strip.children() {
+            self.strip.remove(&child);
+        }
+        (self.names.take(), self.sources.take())
     }
 }
 
@@ -54,16 +463,22 @@ struct UI {
     tabs: Vec<Tabs>,
     tab_count: usize,
     model_count: u32,
-    history_path: PathBuf
+    history_path: PathBuf,
+    models_cache: ModelsCache,
+    // Continue buttons send themselves here when clicked.
+    continues: async_channel::Sender<Button>,
 }
 
 impl UI {
     fn build_ui(app: &Application, config: &Arc<Config>) {
+        let (continue_sender, continue_receiver) = async_channel::unbounded();
         let ui = Rc::new(RefCell::new(UI {
             tabs: Vec::new(),
             tab_count: 0,
             model_count: 0,
             history_path: PathBuf::from(config.general.history_path.clone()),
+            models_cache: ModelsCache::read(&config.general.history_path),
+            continues: continue_sender.clone(),
         }));
         let window = ApplicationWindow::builder()
             .application(app)
@@ -111,14 +526,57 @@ impl UI {
         let send_button = Button::builder().image(&sent_icon).build();
         send_button.style_context().add_class("send-button");
 
+        let stop_icon = gtk::Image::from_icon_name(Some("process-stop-symbolic"), gtk::IconSize::Dnd);
+        let stop_button = Button::builder()
+            .image(&stop_icon)
+            .tooltip_text("Stop (Esc)")
+            .sensitive(false)
+            .build();
+        stop_button.style_context().add_class("stop-button");
+
+        let attach_icon =
+            gtk::Image::from_icon_name(Some("mail-attachment-symbolic"), gtk::IconSize::Dnd);
+        let attach_button = Button::builder()
+            .image(&attach_icon)
+            .tooltip_text("Attach images or text files")
+            .build();
+        attach_button.style_context().add_class("attach-button");
+        let attach_folder_icon =
+            gtk::Image::from_icon_name(Some("folder-symbolic"), gtk::IconSize::Dnd);
+        let attach_folder_button = Button::builder()
+            .image(&attach_folder_icon)
+            .tooltip_text("Attach the text files of a directory")
+            .build();
+        attach_folder_button.style_context().add_class("attach-folder-button");
+        let attachments = Attachments::new(PathBuf::from(&config.general.history_path));
+
         let entry_box_horizontal = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         entry_box_horizontal.pack_start(&entry, true, true, 0);
+        entry_box_horizontal.pack_start(&attach_button, false, false, 0);
+        entry_box_horizontal.pack_start(&attach_folder_button, false, false, 0);
         entry_box_horizontal.pack_start(&send_button, false, false, 0);
+        entry_box_horizontal.pack_start(&stop_button, false, false, 0);
 
         let reset_icon = gtk::Image::from_icon_name(Some("list-add-symbolic"), gtk::IconSize::Dnd);
         let add_tab_button = Button::builder().image(&reset_icon).build();
         add_tab_button.style_context().add_class("truncate-chat");
 
+        let refresh_icon = gtk::Image::from_icon_name(Some("view-refresh-symbolic"), gtk::IconSize::Button);
+        let refresh_models_button = Button::builder()
+            .image(&refresh_icon)
+            .tooltip_text("Fetch the models of every provider")
+            .build();
+        refresh_models_button.style_context().add_class("refresh-models");
+
+        let settings_icon = gtk::Image::from_icon_name(Some("emblem-system-symbolic"), gtk::IconSize::Button);
+        let settings_button = gtk::MenuButton::builder()
+            .image(&settings_icon)
+            .tooltip_text("System prompt and sampling parameters of this conversation")
+            .build();
+        settings_button.style_context().add_class("settings-button");
+        let settings = ConversationSettings::new(&settings_button);
+        settings_button.set_popover(Some(&settings.popover));
+
         let model_combobox = ComboBoxText::new();
         model_combobox.style_context().add_class("model-combobox");
         let model_list = ListStore::new(&[String::static_type()]);
@@ -127,16 +585,79 @@ impl UI {
             ui.borrow_mut().model_count += 1;
         }
         model_combobox.set_model(Some(&model_list));
+
+        // Lists the models of the selected provider.
+        let model_name_combobox = ComboBoxText::new();
+        model_name_combobox.style_context().add_class("model-name-combobox");
+        model_combobox.connect_changed(clone!(@strong config, @weak model_name_combobox, @weak ui => move |combobox| {
+            let provider = combobox
+                .active_text()
+                .and_then(|name| models::find_provider(&config, &name));
+            combobox.set_tooltip_text(
+                provider
+                    .map(|provider| provider.capabilities().to_string())
+                    .as_deref(),
+            );
+            Self::fill_models(combobox, &model_name_combobox, &config, &ui, false);
+        }));
+        model_name_combobox.connect_changed(clone!(@weak model_combobox, @weak ui => move |combobox| {
+            let provider = model_combobox.active_text().unwrap_or_default();
+            let tooltip = combobox.active_id().and_then(|model| {
+                match ui.borrow().models_cache.flag(&provider, &model)? {
+                    ModelFlag::Failed(error) => Some(format!("The last request to this model failed: {}", error)),
+                    ModelFlag::Unlisted => Some(format!("{} does not list this model anymore. It may be deprecated.", provider)),
+                }
+            });
+            combobox.set_tooltip_text(tooltip.as_deref());
+        }));
         model_combobox.set_active(Some(0));
 
         let control_area = gtk::Box::new(gtk::Orientation::Vertical, 0);
         control_area.style_context().add_class("control-area");
         let control_area_horizontal = gtk::Box::new(gtk::Orientation::Horizontal, 0);
 
+        control_area.pack_start(&attachments.strip, false, false, 0);
         control_area.pack_start(&entry_box_horizontal, true, true, 0);
         control_area.pack_start(&control_area_horizontal, false, false, 0);
 
+        // Only shown when prices are configured.
+        let today_label = Label::new(None);
+        today_label.style_context().add_class("label-today");
+        let today_total = Rc::new(RefCell::new(DayTotal::default()));
+        if !config.pricing.is_empty() {
+            // Reading the whole history can take a while, so it's done once and off the UI thread.
+            let (today_sender, today_receiver) = async_channel::bounded(1);
+            let history_config = config.clone();
+            runtime().spawn_blocking(move || {
+                let now = time::now();
+                today_sender.send_blocking((now, usage::day_cost(&history_config, now.div_euclid(86400)))).ok();
+            });
+            glib::spawn_future_local(clone!(@weak today_label, @strong today_total => async move {
+                if let Ok((now, cost)) = today_receiver.recv().await {
+                    Self::set_today_cost(&today_label, today_total.borrow_mut().add(now, cost));
+                }
+            }));
+        }
+
         control_area_horizontal.pack_start(&model_combobox, true, true, 0);
+        control_area_horizontal.pack_start(&model_name_combobox, true, true, 0);
+        control_area_horizontal.pack_start(&refresh_models_button, false, false, 0);
+        control_area_horizontal.pack_start(&settings_button, false, false, 0);
+        if !config.pricing.is_empty() {
+            control_area_horizontal.pack_start(&today_label, false, false, 0);
+        }
+        // One label per MCP server, updated as they connect.
+        let mcp_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        mcp_box.style_context().add_class("mcp-servers");
+        for server in &config.mcp_servers {
+            let label = Label::new(None);
+            label.style_context().add_class("mcp-server");
+            Self::set_mcp_status(&label, &server.name, None);
+            mcp_box.pack_start(&label, false, false, 0);
+        }
+        if !config.mcp_servers.is_empty() {
+            control_area_horizontal.pack_start(&mcp_box, false, false, 0);
+        }
         control_area_horizontal.pack_start(&add_tab_button, false, false, 0);
 
         let main_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
@@ -162,19 +683,44 @@ impl UI {
 
         entry.grab_focus();
 
-        let (sender, receiver) = async_channel::bounded(1);
+        let (sender, receiver) = async_channel::unbounded();
+        let (models_sender, models_receiver) = async_channel::unbounded();
+        // Commands the model wants to run, waiting for the user.
+        let (approvals_sender, approvals_receiver) = async_channel::unbounded();
 
         // Event Handlers.
 
+        // When the last answer stopped generating, so that an Esc meant to stop it
+        // doesn't close the window instead.
+        let generation_ended = Rc::new(Cell::new(None::<Instant>));
+        stop_button.connect_sensitive_notify(clone!(@strong generation_ended => move |button| {
+            if !button.is_sensitive() {
+                generation_ended.set(Some(Instant::now()));
+            }
+        }));
+
         // Key bindings
         window.connect_key_press_event(
-            clone!(@weak send_button, @weak notebook, @weak entry, @weak model_combobox, @weak ui => @default-return Propagation::Proceed, move |window, event| {
+            clone!(@weak send_button, @weak stop_button, @weak notebook, @weak entry, @weak model_combobox, @weak ui, @strong config, @strong settings, @strong attachments, @strong generation_ended => @default-return Propagation::Proceed, move |window, event| {
+            // Lets the settings entries handle their own keys.
+            if settings.popover.is_visible() {
+                return Propagation::Proceed;
+            }
             let modifier = if event.state().is_empty() {
                 None
             } else {
                 Some(event.state())
             };
             let state = match (event.keyval(), modifier) {
+                // Stops the answer being generated first, leaving Ctrl+C to copy text.
+                (keys::Escape, None) if stop_button.is_sensitive() => {
+                    stop_button.emit_clicked();
+                    Propagation::Stop
+                }
+                (keys::Escape, None) if Cell::get(&generation_ended).is_some_and(|ended| ended.elapsed() < Duration::from_secs(1)) => {
+                    Propagation::Stop
+                }
+
                 (keys::Escape, None) => {
                     window.close();
                     Propagation::Stop
@@ -225,6 +771,22 @@ impl UI {
                     Propagation::Stop
                 }
 
+                // Text is left for the entry to paste.
+                (keys::v, Some(ModifierType::CONTROL_MASK)) => {
+                    let clipboard = gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD);
+                    match clipboard.wait_for_image().map(|pixbuf| pixbuf.save_to_bufferv("png", &[])) {
+                        Some(Ok(bytes)) => {
+                            attachments.add(&bytes);
+                            Propagation::Stop
+                        }
+                        Some(Err(error)) => {
+                            eprintln!("Couldn't paste the image: {}", error);
+                            Propagation::Stop
+                        }
+                        None => Propagation::Proceed,
+                    }
+                }
+
                 (keys::Tab, None) => {
                     if model_combobox.is_sensitive() {
                         let next = model_combobox.active().map(|x| {if x == ui.borrow().model_count -1 {0} else {x+1}});
@@ -242,16 +804,15 @@ impl UI {
                 }
 
                 (keys::t, Some(ModifierType::CONTROL_MASK)) => {
-                    Self::new_page(&ui, &notebook, None);
+                    Self::new_page(&ui, &notebook, &config, None);
                     Propagation::Stop
                 }
 
                 (keys::w, Some(ModifierType::CONTROL_MASK)) => {
                     if let Some(page_num) = notebook.current_page() {
                         notebook.remove_page(Some(page_num));
-                        fs::remove_file(ui.borrow().tabs[page_num as usize].file.clone())
-                            .ok();
-                        ui.borrow_mut().tabs.remove(page_num as usize);
+                        let tab = ui.borrow_mut().tabs.remove(page_num as usize);
+                        tab.close();
                     };
                     Propagation::Stop
                 }
@@ -264,48 +825,140 @@ impl UI {
 
         // Adds another tab.
         add_tab_button.connect_clicked(
-            clone!( @weak notebook, @weak model_combobox, @weak ui => move |_| {
-                Self::new_page(&ui, &notebook, None);
+            clone!( @weak notebook, @weak model_combobox, @weak ui, @strong config => move |_| {
+                Self::new_page(&ui, &notebook, &config, None);
                 notebook.show_all();
             }),
         );
 
+        // Attaches images and files from a file chooser, or dropped onto the window.
+        attach_button.connect_clicked(clone!(@weak window, @strong attachments => move |_| {
+            let images = gtk::FileFilter::new();
+            images.set_name(Some("PNG and JPEG images"));
+            images.add_mime_type("image/png");
+            images.add_mime_type("image/jpeg");
+            let text = gtk::FileFilter::new();
+            text.set_name(Some("Text files"));
+            text.add_mime_type("text/*");
+            let all = gtk::FileFilter::new();
+            all.set_name(Some("All files"));
+            all.add_pattern("*");
+            Self::choose_files(&window, gtk::FileChooserAction::Open, &[images, text, all], &attachments);
+        }));
+        attach_folder_button.connect_clicked(clone!(@weak window, @strong attachments => move |_| {
+            Self::choose_files(&window, gtk::FileChooserAction::SelectFolder, &[], &attachments);
+        }));
+
+        window.drag_dest_set(
+            gtk::DestDefaults::ALL,
+            &[gtk::TargetEntry::new("text/uri-list", gtk::TargetFlags::OTHER_APP, 0)],
+            gdk::DragAction::COPY,
+        );
+        window.connect_drag_data_received(clone!(@strong attachments => move |_, _, _, _, data, _, _| {
+            for uri in data.uris() {
+                if let Some(path) = gio::File::for_uri(&uri).path() {
+                    attachments.add_file(&path);
+                }
+            }
+        }));
+
+        // Cancels the answer that is being generated.
+        stop_button.connect_clicked(clone!(@weak ui => move |_| {
+            for tab in &ui.borrow().tabs {
+                if let Some(pending) = &tab.pending {
+                    pending.cancel.notify_one();
+                }
+            }
+        }));
+
         // Sends responses.
         send_button.connect_clicked(
-            clone!(@weak entry, @weak notebook, @weak window, @weak model_combobox, @weak ui, @strong config => move |button| {
+            clone!(@weak entry, @weak notebook, @weak window, @weak model_combobox, @weak model_name_combobox, @weak stop_button, @weak ui, @strong config, @strong attachments, @strong sender, @strong approvals_sender => move |button| {
                 let config = config.clone();
                 let entry_text = entry.text();
-                let selected_model = model_combobox.active_text().unwrap().to_string();
+                let selection = Selection {
+                    provider: model_combobox.active_text().unwrap().to_string(),
+                    model: model_name_combobox.active_id().map(|model| model.to_string()).unwrap_or_default(),
+                };
 
                 if !entry_text.is_empty() {
 
+                    let (images, files) = attachments.take();
+                    let question = ChatContent {
+                        question: entry_text.to_string(),
+                        images,
+                        files,
+                        ..Default::default()
+                    };
                     let answer_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
                     let new_question_label = Self::new_label(entry_text.as_str(), true, false);
 
+                    if !question.images.is_empty() {
+                        answer_box.pack_start(&Self::thumbnails(&attachments.history_path, &question.images), false, false, 0);
+                    }
+                    if !question.files.is_empty() {
+                        let attached = Self::attached_files(&question.files, &question.context);
+                        attached.set_tooltip_text(Some("Reading the files…"));
+                        answer_box.pack_start(&attached, false, false, 0);
+                    }
                     answer_box.pack_start(&new_question_label, false, false, 0);
                     answer_box.set_halign(gtk::Align::End);
                     answer_box.style_context().add_class("label-user");
 
 
+                    let tools_box = Self::tools_box();
+
+                    let model_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
+                    model_box.set_halign(gtk::Align::Start);
+                    model_box.style_context().add_class("label-model");
+
                     let page_number = notebook.current_page().unwrap_or_else(|| {
-                        Self::new_page(&ui, &notebook, None);
+                        Self::new_page(&ui, &notebook, &config, None);
                         0
                     });
-                    ui.borrow_mut().tabs[page_number as usize].model = Some(selected_model.clone());
+                    ui.borrow_mut().tabs[page_number as usize].model = Some(selection.clone());
+                    let cancel = Arc::new(Notify::new());
+                    ui.borrow_mut().tabs[page_number as usize].pending = Some(Pending {
+                        question_box: answer_box.clone(),
+                        tools_box: tools_box.clone(),
+                        answer_box: model_box.clone(),
+                        question: question.clone(),
+                        text: String::new(),
+                        cancel: cancel.clone(),
+                        thinking: None,
+                        previous: None,
+                    });
                     let current_page = &ui.borrow().tabs[page_number as usize ];
                     current_page.tab.pack_start(&answer_box, false, false, 0);
-                    let current_page_id = current_page.id.clone();
-                    let file = current_page.clone().file;
+                    current_page.tab.pack_start(&tools_box, false, false, 0);
+                    current_page.tab.pack_start(&model_box, false, false, 0);
+                    let current_page_id = current_page.id;
+                    let file = current_page.file.clone();
                     entry.delete_text(0, -1);
                     entry.set_sensitive(false);
                     button.set_sensitive(false);
+                    stop_button.set_sensitive(true);
                     model_combobox.set_sensitive(false);
+                    model_name_combobox.set_sensitive(false);
                     window.show_all();
 
-                    runtime().spawn(clone!(@strong sender => async move {
-                        let response = models::select_model(&selected_model, &entry_text, config, file).await;
-                        sender.send((response, current_page_id)).await.expect("The channel needs to be open.");
+                    let limits = config.files.clone();
+                    runtime().spawn(clone!(@strong sender, @strong approvals_sender => async move {
+                        let mut question = question;
+                        if !question.files.is_empty() {
+                            question.context = read_files(question.files.clone(), limits).await;
+                            sender.send((Update::Context(question.context.clone()), current_page_id)).await.ok();
+                        }
+                        // Dropping the request future stops the download and skips caching,
+                        // so the UI gets either the full answer or a cancellation, never both.
+                        let update = tokio::select! {
+                            response = models::select_model(&selection, &question, config, file, &approvals_sender, |progress| {
+                                sender.try_send((Update::from(progress), current_page_id)).ok();
+                            }) => Update::Done(Box::new(response)),
+                            _ = cancel.notified() => Update::Cancelled,
+                        };
+                        sender.send((update, current_page_id)).await.expect("The channel needs to be open.");
                     }));
                 }
             }),
@@ -313,19 +966,22 @@ impl UI {
 
         // Handles tab switching.
         let inhibit_notebook = notebook.connect_switch_page(
-            clone!(@weak ui, @weak config => move |notebook, _, page| {
+            clone!(@weak ui, @weak config, @weak model_combobox, @weak model_name_combobox => move |notebook, _, page| {
                 if notebook.children().len() != 0 {
                     if let Some(file) = &ui.borrow().tabs.get(page as usize) {
-                        if let Some(model) = file.model.clone() {
+                        if let Some(selection) = file.model.clone() {
                             let index = get_models(&config)
                                 .iter()
-                                .position(|r| r == &model)
+                                .position(|r| r == &selection.provider)
                                 .unwrap_or_default();
                             model_combobox.set_active(Some(index as u32));
+                            Self::set_active_model(&model_name_combobox, &selection.model);
                             model_combobox.set_sensitive(false);
+                            model_name_combobox.set_sensitive(false);
                         } else {
                             model_combobox.set_active(Some(0));
                             model_combobox.set_sensitive(true);
+                            model_name_combobox.set_sensitive(true);
                         }
                     }
                 }
@@ -334,36 +990,333 @@ impl UI {
 
         // Handles the api call to the llm and adds Label widget.
         glib::spawn_future_local(
-            clone!(@weak notebook, @weak window, @weak entry, @weak ui, @weak config => async move {
-                while let Ok((response, current_page_id)) = receiver.recv().await {
-                    entry.set_sensitive(true);
-                    send_button.set_sensitive(true);
-                    let label_content = if let Ok(response) = response {
-                        if response.status.is_success() {
-                            response.answer
-                        } else {
-                            response.status.to_string()
+            clone!(@weak notebook, @weak window, @weak entry, @weak send_button, @weak stop_button, @weak today_label, @weak model_combobox, @weak model_name_combobox, @weak ui, @weak config, @strong today_total, @strong attachments, @strong continue_sender => async move {
+                while let Ok((update, current_page_id)) = receiver.recv().await {
+                    let mut ui_ref = ui.borrow_mut();
+                    let tab = Tabs::get_tab_from_id(current_page_id, &mut ui_ref.tabs);
+                    // The provider, model and error of a finished request, to flag failing models.
+                    let mut model_result = None;
+                    match update {
+                        Update::Chunk(chunk) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_mut()) {
+                                pending.text.push_str(&chunk);
+                                match &pending.previous {
+                                    Some(previous) => Self::set_answer(&format!("{}{}", previous.answer, pending.text), &pending.answer_box, &config),
+                                    None => Self::set_answer(&pending.text, &pending.answer_box, &config),
+                                }
+                            }
+                        }
+                        // Kept above any tool calls, like the thinking shown for saved answers.
+                        Update::Thinking(thinking) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_mut()) {
+                                match &pending.thinking {
+                                    Some(label) => label.set_text(&format!("{}{}", label.text(), thinking)),
+                                    None => {
+                                        let (expander, label) = Self::thinking_expander(&thinking);
+                                        pending.tools_box.pack_start(&expander, false, false, 0);
+                                        pending.tools_box.reorder_child(&expander, 0);
+                                        pending.thinking = Some(label);
+                                    }
+                                }
+                            }
+                        }
+                        Update::Retrying(delay) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_ref()) {
+                                Self::set_status(&format!("Retrying in {}s…", delay.as_secs_f64().ceil()), &pending.answer_box);
+                            }
+                        }
+                        Update::Context(context) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_mut()) {
+                                let attached = pending.question_box.children().into_iter().find(|child| child.style_context().has_class("attached-files"));
+                                if let Some(attached) = attached.and_then(|child| child.downcast::<gtk::Box>().ok()) {
+                                    Self::set_files_tooltip(&attached, &context);
+                                }
+                                pending.question.context = context;
+                            }
+                        }
+                        Update::ToolCall(call) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_ref()) {
+                                pending.tools_box.pack_start(&Self::tool_bubble(&call), false, false, 0);
+                            }
+                        }
+                        // Tools run one at a time, so the result belongs to the last bubble.
+                        Update::ToolResult(result) => {
+                            if let Some(pending) = tab.and_then(|tab| tab.pending.as_ref()) {
+                                if let Some(bubble) = pending.tools_box.children().last() {
+                                    Self::set_tool_result(bubble, &result);
+                                }
+                            }
                         }
+                        Update::Done(response) => {
+                            entry.set_sensitive(true);
+                            send_button.set_sensitive(true);
+                            stop_button.set_sensitive(false);
+                            if let Some(tab) = tab {
+                                if let Some(pending) = tab.pending.take() {
+                                    let selection = tab.model.clone().unwrap_or_default();
+                                    match *response {
+                                        Ok(response) => {
+                                            if let Some(usage) = response.usage {
+                                                let cost = config.price(&selection.provider, &response.model).map(|price| price.cost(&usage));
+                                                tab.add_usage(usage, cost);
+                                                if let Some(cost) = cost {
+                                                    Self::set_today_cost(&today_label, today_total.borrow_mut().add(time::now(), cost));
+                                                }
+                                            }
+                                            // A continuation is shown as part of the answer it continues.
+                                            let answer = match pending.previous {
+                                                Some(mut previous) => {
+                                                    previous.extend(&response);
+                                                    previous
+                                                }
+                                                None => response,
+                                            };
+                                            Self::show_answer(&answer, &pending.answer_box, &selection.provider, &config, &continue_sender);
+                                            model_result = Some((selection.provider, answer.model, None));
+                                        }
+                                        Err(error) => {
+                                            // The answer being continued stays, so it can be continued again.
+                                            match &pending.previous {
+                                                Some(previous) => {
+                                                    Self::show_answer(previous, &pending.answer_box, &selection.provider, &config, &continue_sender);
+                                                    let message = format!("{}: {}", error.title(), error);
+                                                    pending.answer_box.pack_start(&Self::notice_label(&message), false, false, 0);
+                                                }
+                                                None => Self::set_error(&error, &pending, &notebook, &entry, &send_button, &attachments),
+                                            }
+                                            // Unknown or retired models are rejected with these.
+                                            if let ConverseError::Status { status, .. } = &error {
+                                                if matches!(status.as_u16(), 400 | 404) {
+                                                    let model = if selection.model.is_empty() {
+                                                        models::find_provider(&config, &selection.provider)
+                                                            .map(|provider| provider.model().to_string())
+                                                            .unwrap_or_default()
+                                                    } else {
+                                                        selection.model
+                                                    };
+                                                    model_result = Some((selection.provider, model, Some(error.to_string())));
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Update::Cancelled => {
+                            entry.set_sensitive(true);
+                            send_button.set_sensitive(true);
+                            stop_button.set_sensitive(false);
+                            if let Some(tab) = tab {
+                                if let Some(pending) = tab.pending.take() {
+                                    pending.answer_box.pack_start(&Self::interrupted_label(), false, false, 0);
+                                    let thinking = pending.thinking.map(|label| label.text().to_string()).unwrap_or_default();
+                                    let saved = match pending.previous {
+                                        Some(mut previous) => {
+                                            previous.extend(&ChatContent {
+                                                answer: pending.text,
+                                                thinking,
+                                                interrupted: true,
+                                                ..Default::default()
+                                            });
+                                            Cache::replace_answer(tab.file.clone(), &previous)
+                                        }
+                                        // Nothing arrived, so there is no answer to send back later.
+                                        None if pending.text.is_empty() => Ok(()),
+                                        None => {
+                                            let partial = ChatContent {
+                                                answer: pending.text,
+                                                thinking,
+                                                interrupted: true,
+                                                ..pending.question
+                                            };
+                                            Cache::update_conversation(tab.file.clone(), &partial, &tab.model.clone().unwrap_or_default())
+                                        }
+                                    };
+                                    if let Err(error) = saved {
+                                        eprintln!("{}: {}", error.title(), error);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    drop(ui_ref);
+                    if let Some((provider, model, error)) = model_result.filter(|(_, model, _)| !model.is_empty()) {
+                        let changed = ui.borrow_mut().models_cache.set_failed(&provider, &model, error);
+                        if changed {
+                            if let Err(error) = ui.borrow().models_cache.write(&config.general.history_path) {
+                                eprintln!("{}: {}", error.title(), error);
+                            }
+                            Self::fill_models(&model_combobox, &model_name_combobox, &config, &ui, true);
+                        }
+                    }
+                    window.show_all();
+                }
+            }),
+        );
+
+        // Asks for the rest of an answer that was cut off at the token limit.
+        glib::spawn_future_local(
+            clone!(@weak window, @weak entry, @weak send_button, @weak stop_button, @weak ui, @strong config, @strong sender, @strong approvals_sender => async move {
+                while let Ok(button) = continue_receiver.recv().await {
+                    if !send_button.is_sensitive() {
+                        continue;
                     }
-                    else {
-                        "Could not connect to a server.".to_string()
+                    let Some(answer_box) = button.parent().and_then(|parent| parent.downcast::<gtk::Box>().ok()) else {
+                        continue;
+                    };
+                    let mut ui_ref = ui.borrow_mut();
+                    let Some(tab) = ui_ref.tabs.iter_mut().find(|tab| answer_box.parent().as_ref() == Some(tab.tab.upcast_ref())) else {
+                        continue;
+                    };
+                    // Only the last answer can be continued.
+                    let previous = Cache::read(&tab.file)["chat"]
+                        .as_array()
+                        .and_then(|chat| chat.last())
+                        .filter(|item| item["role"] == "model" && item["truncated"] == true)
+                        .map(Cache::answer);
+                    let is_last = tab.tab.children().last() == Some(answer_box.upcast_ref());
+                    let (Some(previous), Some(selection), true) = (previous, tab.model.clone(), is_last) else {
+                        answer_box.remove(&button);
+                        continue;
                     };
 
-                    let answer_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
+                    Self::set_answer(&previous.answer, &answer_box, &config);
+                    let tools_box = Self::tools_box();
+                    tab.tab.pack_start(&tools_box, false, false, 0);
+                    tab.tab.reorder_child(&tools_box, tab.tab.children().len() as i32 - 2);
+                    let cancel = Arc::new(Notify::new());
+                    tab.pending = Some(Pending {
+                        question_box: gtk::Box::new(gtk::Orientation::Vertical, 0),
+                        tools_box,
+                        answer_box,
+                        question: ChatContent::default(),
+                        text: String::new(),
+                        cancel: cancel.clone(),
+                        thinking: None,
+                        previous: Some(previous),
+                    });
+                    let current_page_id = tab.id;
+                    let file = tab.file.clone();
+                    drop(ui_ref);
+                    entry.set_sensitive(false);
+                    send_button.set_sensitive(false);
+                    stop_button.set_sensitive(true);
+                    window.show_all();
 
-                    for block in md2pango(&label_content, &config) {
-                        Self::model_response_format(block, &answer_box);
-                        answer_box.set_halign(gtk::Align::Start);
-                        answer_box.style_context().add_class("label-model");
+                    let config = config.clone();
+                    runtime().spawn(clone!(@strong sender, @strong approvals_sender => async move {
+                        let update = tokio::select! {
+                            response = models::continue_answer(&selection, config, file, &approvals_sender, |progress| {
+                                sender.try_send((Update::from(progress), current_page_id)).ok();
+                            }) => Update::Done(Box::new(response)),
+                            _ = cancel.notified() => Update::Cancelled,
+                        };
+                        sender.send((update, current_page_id)).await.expect("The channel needs to be open.");
+                    }));
+                }
+            }),
+        );
+
+        // Shows the settings of the current conversation.
+        settings.popover.connect_show(clone!(@weak notebook, @weak model_combobox, @weak ui, @strong config, @strong settings => move |_| {
+            let (system_prompt, generation) = notebook
+                .current_page()
+                .and_then(|page| {
+                    ui.borrow()
+                        .tabs
+                        .get(page as usize)
+                        .map(|tab| (tab.system_prompt.clone(), tab.generation.clone()))
+                })
+                .unwrap_or_default();
+            let provider = model_combobox
+                .active_text()
+                .and_then(|name| models::find_provider(&config, &name));
+            let (default_prompt, defaults) = provider
+                .map(|provider| (provider.system_prompt().to_string(), provider.generation().clone()))
+                .unwrap_or_default();
+            settings.set(&system_prompt, &generation, &default_prompt, &defaults);
+        }));
+
+        settings.apply_button.connect_clicked(clone!(@weak notebook, @weak ui, @strong settings => move |_| {
+            let Some((system_prompt, generation)) = settings.get() else {
+                return;
+            };
+            if let Some(page) = notebook.current_page() {
+                if let Some(tab) = ui.borrow_mut().tabs.get_mut(page as usize) {
+                    if let Err(error) = Cache::set_settings(&tab.file, &generation, &system_prompt) {
+                        eprintln!("{}: {}", error.title(), error);
+                    }
+                    tab.system_prompt = system_prompt;
+                    tab.generation = generation;
+                }
+            }
+            settings.popover.popdown();
+        }));
+
+        // Fetches the model lists, at startup only when they are out of date.
+        refresh_models_button.connect_clicked(clone!(@strong config, @strong models_sender => move |_| {
+            Self::refresh_models(&config, None, &models_sender);
+        }));
+        let stale: Vec<String> = get_models(config)
+            .into_iter()
+            .filter(|provider| ui.borrow().models_cache.is_stale(provider))
+            .collect();
+        Self::refresh_models(config, Some(stale), &models_sender);
+
+        glib::spawn_future_local(
+            clone!(@weak model_combobox, @weak model_name_combobox, @weak ui, @weak config => async move {
+                while let Ok((provider, models)) = models_receiver.recv().await {
+                    let mut ui_ref = ui.borrow_mut();
+                    ui_ref.models_cache.update(&provider, models);
+                    if let Err(error) = ui_ref.models_cache.write(&config.general.history_path) {
+                        eprintln!("{}: {}", error.title(), error);
                     }
-                    if let Some(tab) = Tabs::get_tab_from_id(current_page_id, &ui.borrow().tabs) {
-                        tab.pack_start(&answer_box, false, false, 0);
+                    drop(ui_ref);
+                    if model_combobox.active_text().as_deref() == Some(provider.as_str()) {
+                        Self::fill_models(&model_combobox, &model_name_combobox, &config, &ui, true);
                     }
-                    window.show_all();
                 }
             }),
         );
 
+        let (mcp_sender, mcp_receiver) = async_channel::unbounded();
+        for (index, server) in config.mcp_servers.iter().enumerate() {
+            let sender = mcp_sender.clone();
+            runtime().spawn(mcp::connect(server.clone(), move |status| {
+                sender.try_send((index, status)).ok();
+            }));
+        }
+        glib::spawn_future_local(clone!(@weak mcp_box, @weak config => async move {
+            while let Ok((index, status)) = mcp_receiver.recv().await {
+                if let Some(label) = mcp_box.children().get(index).and_then(|child| child.downcast_ref::<Label>()) {
+                    Self::set_mcp_status(label, &config.mcp_servers[index].name, Some(&status));
+                }
+            }
+        }));
+
+        // Commands matching an allowed pattern are approved without asking.
+        let allowed = Rc::new(RefCell::new(config.tools.shell.allow.clone()));
+        glib::spawn_future_local(clone!(@weak window, @weak config => async move {
+            while let Ok(request) = approvals_receiver.recv().await {
+                let approved = if shell::is_allowed(&allowed.borrow(), &request.command) {
+                    true
+                } else {
+                    match Self::approve_command(&window, &request).await {
+                        Some(pattern) if !pattern.is_empty() => {
+                            if let Err(error) = config.add_shell_pattern(&pattern) {
+                                eprintln!("Couldn't save the pattern: {}", error);
+                            }
+                            allowed.borrow_mut().push(pattern);
+                            true
+                        }
+                        Some(_) => true,
+                        None => false,
+                    }
+                };
+                request.reply.send(approved).ok();
+            }
+        }));
+
         let file_list = Cache::read_all(config.general.history_path.clone());
         if file_list.len() != 0 {
             for file in file_list {
@@ -385,13 +1338,34 @@ impl UI {
         inhibit_notebook: &SignalHandlerId,
     ) -> Option<String> {
         notebook.block_signal(inhibit_notebook);
-        let (chat_box_layout, chats) = Self::new_page(ui, notebook, dir_file.clone());
-        if chats["chat"] != json!([]) {
-            for chat in chats["chat"].as_array().unwrap() {
-                let answer = chat["text"].as_str().unwrap();
+        let (chat_box_layout, chats) = Self::new_page(ui, notebook, config, dir_file.clone());
+        let items = chats["chat"].as_array().map(Vec::as_slice).unwrap_or_default();
+        if !items.is_empty() {
+            let provider = Cache::selection(&chats).unwrap_or_default().provider;
+            for (index, chat) in items.iter().enumerate() {
+                // Hand edited or damaged histories are shown without the broken messages.
+                let Some(answer) = chat["text"].as_str() else {
+                    let file = dir_file.as_ref().map(|file| file.display().to_string()).unwrap_or_default();
+                    eprintln!("Could not read the history: message {} in {} has no text.", index + 1, file);
+                    continue;
+                };
                 let answer_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
                 if chat["role"] == "user" {
+                    let sources: Vec<FileSource> = serde_json::from_value(chat["files"].clone()).unwrap_or_default();
+                    if !sources.is_empty() {
+                        let context: Vec<ContextFile> = serde_json::from_value(chat["context"].clone()).unwrap_or_default();
+                        let attached = Self::attached_files(&sources, &context);
+                        if let Some(file) = &dir_file {
+                            Self::add_reread_button(&attached, file, index, sources, context, &config.files);
+                        }
+                        answer_box.pack_start(&attached, false, false, 0);
+                    }
+                    let images = Cache::images(chat);
+                    if !images.is_empty() {
+                        let thumbnails = Self::thumbnails(&ui.borrow().history_path, &images);
+                        answer_box.pack_start(&thumbnails, false, false, 0);
+                    }
                     let label_user = Self::new_label(answer, true, false);
                     answer_box.pack_start(&label_user, false, false, 0);
                     answer_box.set_halign(gtk::Align::End);
@@ -402,6 +1376,40 @@ impl UI {
                         answer_box.set_halign(gtk::Align::Start);
                         answer_box.style_context().add_class("label-model");
                     }
+                    let sources: Vec<Source> = serde_json::from_value(chat["sources"].clone()).unwrap_or_default();
+                    let searches: Vec<String> = serde_json::from_value(chat["searches"].clone()).unwrap_or_default();
+                    if !sources.is_empty() || !searches.is_empty() {
+                        answer_box.pack_start(&Self::sources_label(&sources, &searches), false, false, 0);
+                    }
+                    if let Some(notice) = chat["notice"].as_str() {
+                        answer_box.pack_start(&Self::notice_label(notice), false, false, 0);
+                    }
+                    if chat["interrupted"] == true {
+                        answer_box.pack_start(&Self::interrupted_label(), false, false, 0);
+                    }
+                    if let Some(answer) = usage::answer(chat, &provider, 0) {
+                        let label = Self::answer_usage_label(&answer.usage, answer.cost(config));
+                        answer_box.pack_start(&label, false, false, 0);
+                    }
+                    if chat["truncated"] == true && index + 1 == items.len() {
+                        answer_box.pack_start(&Self::continue_button(&ui.borrow().continues), false, false, 0);
+                    }
+                    let thinking = chat["thinking"].as_str().unwrap_or_default();
+                    let rounds: Vec<ToolRound> = serde_json::from_value(chat["tools"].clone()).unwrap_or_default();
+                    if !thinking.is_empty() || !rounds.is_empty() {
+                        let tools_box = Self::tools_box();
+                        if !thinking.is_empty() {
+                            tools_box.pack_start(&Self::thinking_expander(thinking).0, false, false, 0);
+                        }
+                        for round in rounds {
+                            for (call, result) in round.calls.iter().zip(&round.results) {
+                                let bubble = Self::tool_bubble(call);
+                                Self::set_tool_result(&bubble, result);
+                                tools_box.pack_start(&bubble, false, false, 0);
+                            }
+                        }
+                        chat_box_layout.pack_start(&tools_box, false, false, 0);
+                    }
                 };
 
                 chat_box_layout.pack_start(&answer_box, false, false, 0);
@@ -415,6 +1423,7 @@ impl UI {
     fn new_page(
         ui: &Rc<RefCell<UI>>,
         notebook: &gtk::Notebook,
+        config: &Config,
         file: Option<PathBuf>,
     ) -> (gtk::Box, serde_json::Value) {
         let chat_box_layout = gtk::Box::new(gtk::Orientation::Vertical, 0);
@@ -431,7 +1440,10 @@ impl UI {
         let close_button = gtk::Button::new();
         close_button.set_relief(gtk::ReliefStyle::None);
         close_button.set_image(Some(&close_image));
+        let usage_label = gtk::Label::new(None);
+        usage_label.style_context().add_class("label-usage");
         tab.pack_start(&tab_label, true, true, 0);
+        tab.pack_start(&usage_label, true, true, 0);
         tab.pack_end(&close_button, true, true, 0);
         tab.show_all();
 
@@ -440,8 +1452,8 @@ impl UI {
 
         close_button.connect_clicked(clone!(@weak notebook, @strong ui => move |_| {
             let index = notebook.page_num(&scroll).expect("Couldn't get page_num from notebook");
-            fs::remove_file(ui.borrow().tabs[index as usize].file.clone()).ok();
-            ui.borrow_mut().tabs.remove(index as usize);
+            let tab = ui.borrow_mut().tabs.remove(index as usize);
+            tab.close();
             notebook.remove_page(Some(index));
         }));
 
@@ -450,17 +1462,25 @@ impl UI {
         let file =
             file.unwrap_or_else(|| ui.borrow().history_path.join(real_time().to_string() + "-history.json"));
         let chats = Cache::read(&file);
-        let model = if let Some(model) = chats["model"].as_str() {
-            Some(model.to_string())
-        } else {
-            None
-        };
-        ui.borrow_mut().tabs.push(Tabs {
+        let model = Cache::selection(&chats);
+        let system_prompt = Cache::system_prompt(&chats).to_string();
+        let generation = Cache::generation(&chats);
+        let mut tab = Tabs {
             tab: chat_box_layout.clone(),
             id: tab_id,
             file,
             model,
-        });
+            pending: None,
+            system_prompt,
+            generation,
+            usage: Usage::default(),
+            cost: None,
+            usage_label,
+        };
+        for answer in usage::answers(&chats, 0) {
+            tab.add_usage(answer.usage, answer.cost(config));
+        }
+        ui.borrow_mut().tabs.push(tab);
         (chat_box_layout, chats)
     }
 
@@ -485,6 +1505,513 @@ impl UI {
         answer_label
     }
 
+    // Tokens used by a single answer, and their cost when the model has a price.
+    fn answer_usage_label(usage: &Usage, cost: Option<f64>) -> Label {
+        let mut text = format!("{} in", format_tokens(usage.input_tokens + usage.cache_creation_tokens));
+        if usage.cache_read_tokens > 0 {
+            text.push_str(&format!(" · {} cached", format_tokens(usage.cache_read_tokens)));
+        }
+        text.push_str(&format!(" · {} out", format_tokens(usage.output_tokens)));
+        if let Some(cost) = cost {
+            text.push_str(&format!(" · {}", format_cost(cost)));
+        }
+        let label = Label::new(Some(&text));
+        label.set_tooltip_text(Some(&usage_tooltip(usage)));
+        label.set_halign(gtk::Align::Start);
+        label.style_context().add_class("label-answer-usage");
+        label
+    }
+
+    fn set_today_cost(label: &Label, cost: f64) {
+        label.set_text(&format!("Today: {}", format_cost(cost)));
+    }
+
+    // Lists the configured and fetched models of the selected provider, flagging the
+    // ones that failed or are no longer listed.
+    fn fill_models(
+        model_combobox: &ComboBoxText,
+        model_name_combobox: &ComboBoxText,
+        config: &Config,
+        ui: &Rc<RefCell<UI>>,
+        keep_selection: bool,
+    ) {
+        let active = model_name_combobox.active_id();
+        model_name_combobox.remove_all();
+        let Some(provider) = model_combobox
+            .active_text()
+            .and_then(|name| models::find_provider(config, &name))
+        else {
+            return;
+        };
+
+        let ui_ref = ui.borrow();
+        let mut models = provider.models();
+        if models.is_empty() {
+            model_name_combobox.append(None, "Default");
+        }
+        for model in ui_ref.models_cache.listed(provider.name()) {
+            if !models.contains(model) {
+                models.push(model.clone());
+            }
+        }
+        for model in &models {
+            let label = match ui_ref.models_cache.flag(provider.name(), model) {
+                Some(ModelFlag::Failed(_)) => format!("{} (failed)", model),
+                Some(ModelFlag::Unlisted) => format!("{} (not listed)", model),
+                None => model.clone(),
+            };
+            model_name_combobox.append(Some(model), &label);
+        }
+        drop(ui_ref);
+
+        match active {
+            Some(model) if keep_selection => Self::set_active_model(model_name_combobox, &model),
+            _ => model_name_combobox.set_active(Some(0)),
+        }
+    }
+
+    // Lists the models of the given providers, or of all of them, in the background.
+    fn refresh_models(
+        config: &Arc<Config>,
+        names: Option<Vec<String>>,
+        sender: &async_channel::Sender<(String, Result<Vec<String>, ConverseError>)>,
+    ) {
+        let config = config.clone();
+        let sender = sender.clone();
+        runtime().spawn(async move {
+            for provider in models::providers(&config) {
+                if names.as_ref().is_some_and(|names| !names.iter().any(|name| name == provider.name())) {
+                    continue;
+                }
+                let models = models::list_models(provider.as_ref()).await;
+                sender.send((provider.name().to_string(), models)).await.ok();
+            }
+        });
+    }
+
+    // Asks whether to run the command. Returns the pattern to always allow, which is empty
+    // when only this command was approved, or None when it was denied.
+    async fn approve_command(window: &ApplicationWindow, request: &CommandRequest) -> Option<String> {
+        let dialog = gtk::Dialog::builder()
+            .title("Run this command?")
+            .transient_for(window)
+            .modal(true)
+            .build();
+        dialog.style_context().add_class("command-approval");
+        let default_pattern = shell::default_pattern(&request.command);
+        dialog.add_button("Deny", gtk::ResponseType::Reject);
+        if default_pattern.is_some() {
+            dialog.add_button("Always allow", gtk::ResponseType::Other(1));
+        }
+        dialog.add_button("Approve", gtk::ResponseType::Accept);
+
+        let command = Label::builder()
+            .label(&request.command)
+            .selectable(true)
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        command.style_context().add_class("command");
+        let working_dir = Label::builder()
+            .label(format!("in {}", request.working_dir.display()))
+            .xalign(0.0)
+            .build();
+        let pattern_label = Label::builder()
+            .label(match default_pattern {
+                Some(_) => "Always allow commands matching, in any directory:",
+                None => "Commands with ;, |, &, redirections or substitutions are always asked about.",
+            })
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let pattern = Entry::builder()
+            .text(default_pattern.as_deref().unwrap_or_default())
+            .build();
+        pattern.style_context().add_class("command-pattern");
+
+        let content = dialog.content_area();
+        content.set_spacing(5);
+        content.pack_start(&command, false, false, 0);
+        content.pack_start(&working_dir, false, false, 0);
+        content.pack_start(&pattern_label, false, false, 0);
+        if default_pattern.is_some() {
+            content.pack_start(&pattern, false, false, 0);
+        }
+        dialog.show_all();
+
+        let response = dialog.run_future().await;
+        let pattern = pattern.text().trim().to_string();
+        dialog.close();
+        match response {
+            gtk::ResponseType::Accept => Some(String::new()),
+            gtk::ResponseType::Other(1) => Some(pattern),
+            _ => None,
+        }
+    }
+
+    // None while the server is starting.
+    fn set_mcp_status(label: &Label, name: &str, status: Option<&McpStatus>) {
+        let (text, tooltip) = match status {
+            None => (format!("{} (connecting)", name), "Starting the MCP server".to_string()),
+            Some(McpStatus::Connected(tools)) if tools.is_empty() => {
+                (name.to_string(), "Connected, without any tools".to_string())
+            }
+            Some(McpStatus::Connected(tools)) => (name.to_string(), format!("Tools: {}", tools.join(", "))),
+            Some(McpStatus::Failed(error)) => (format!("{} (failed)", name), error.clone()),
+            Some(McpStatus::Stopped) => (format!("{} (stopped)", name), "The MCP server has exited".to_string()),
+        };
+        label.set_text(&text);
+        label.set_tooltip_text(Some(&tooltip));
+        if matches!(status, Some(McpStatus::Connected(_))) {
+            label.style_context().remove_class("mcp-server-down");
+        } else {
+            label.style_context().add_class("mcp-server-down");
+        }
+    }
+
+    // Models loaded from history may no longer be in the config, so they are added to the list.
+    fn set_active_model(combobox: &ComboBoxText, model: &str) {
+        if model.is_empty() {
+            combobox.set_active(Some(0));
+        } else if !combobox.set_active_id(Some(model)) {
+            combobox.append(Some(model), model);
+            combobox.set_active_id(Some(model));
+        }
+    }
+
+    fn choose_files(
+        window: &ApplicationWindow,
+        action: gtk::FileChooserAction,
+        filters: &[gtk::FileFilter],
+        attachments: &Attachments,
+    ) {
+        let dialog = gtk::FileChooserNative::new(
+            Some("Attach"),
+            Some(window),
+            action,
+            Some("Attach"),
+            None,
+        );
+        dialog.set_select_multiple(true);
+        for filter in filters {
+            dialog.add_filter(filter.clone());
+        }
+        if dialog.run() == gtk::ResponseType::Accept {
+            for file in dialog.files() {
+                if let Some(path) = file.path() {
+                    attachments.add_file(&path);
+                }
+            }
+        }
+    }
+
+    // Images that were deleted since are shown as a missing image.
+    fn thumbnail(history_path: &std::path::Path, name: &str) -> gtk::Image {
+        let path = Cache::attachments_dir(history_path).join(name);
+        match gtk::gdk_pixbuf::Pixbuf::from_file_at_scale(&path, 120, 120, true) {
+            Ok(pixbuf) => gtk::Image::from_pixbuf(Some(&pixbuf)),
+            Err(_) => gtk::Image::from_icon_name(Some("image-missing"), gtk::IconSize::Dialog),
+        }
+    }
+
+    // Lists the files read from each attached file or directory in the tooltip.
+    fn attached_files(sources: &[FileSource], context: &[ContextFile]) -> gtk::Box {
+        let attached = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+        attached.set_halign(gtk::Align::End);
+        attached.style_context().add_class("attached-files");
+        let names: Vec<String> = sources
+            .iter()
+            .map(|source| {
+                source.path.file_name().map_or(source.path.display().to_string(), |name| {
+                    name.to_string_lossy().to_string()
+                })
+            })
+            .collect();
+        let label = Label::new(Some(&names.join(", ")));
+        label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+        attached.pack_start(&label, false, false, 0);
+        Self::set_files_tooltip(&attached, context);
+        attached
+    }
+
+    fn set_files_tooltip(attached: &gtk::Box, context: &[ContextFile]) {
+        let tooltip = if context.is_empty() {
+            "No files could be read.".to_string()
+        } else {
+            context
+                .iter()
+                .map(|file| file.path.display().to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        attached.set_tooltip_text(Some(&tooltip));
+    }
+
+    // Offers to read the files of a question from the history again, when they
+    // changed on disk since it was sent.
+    fn add_reread_button(
+        attached: &gtk::Box,
+        file: &std::path::Path,
+        index: usize,
+        sources: Vec<FileSource>,
+        context: Vec<ContextFile>,
+        limits: &ConfigFiles,
+    ) {
+        let file = file.to_path_buf();
+        let limits = limits.clone();
+        glib::spawn_future_local(clone!(@weak attached => async move {
+            if read_files(sources.clone(), limits.clone()).await == context {
+                return;
+            }
+            let reread_button = Button::builder()
+                .label("Re-read")
+                .tooltip_text("The files changed since this was sent. Read them again for the next questions.")
+                .build();
+            reread_button.style_context().add_class("reread-files");
+            attached.pack_start(&reread_button, false, false, 0);
+            reread_button.show();
+
+            reread_button.connect_clicked(clone!(@weak attached => move |button| {
+                button.set_sensitive(false);
+                glib::spawn_future_local(clone!(@weak attached, @weak button, @strong file, @strong sources, @strong limits => async move {
+                    let context = read_files(sources, limits).await;
+                    if let Err(error) = Cache::set_context(&file, index, &context) {
+                        eprintln!("{}: {}", error.title(), error);
+                        button.set_sensitive(true);
+                        return;
+                    }
+                    Self::set_files_tooltip(&attached, &context);
+                    attached.remove(&button);
+                }));
+            }));
+        }));
+    }
+
+    fn thumbnails(history_path: &std::path::Path, names: &[String]) -> gtk::Box {
+        let thumbnails = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+        thumbnails.set_halign(gtk::Align::End);
+        thumbnails.style_context().add_class("thumbnails");
+        for name in names {
+            thumbnails.pack_start(&Self::thumbnail(history_path, name), false, false, 0);
+        }
+        thumbnails
+    }
+
+    fn tools_box() -> gtk::Box {
+        let tools_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        tools_box.set_halign(gtk::Align::Start);
+        tools_box.style_context().add_class("tool-calls");
+        tools_box
+    }
+
+    // Collapsed, so the reasoning doesn't push the answer out of view.
+    fn thinking_expander(thinking: &str) -> (gtk::Expander, Label) {
+        let expander = gtk::Expander::new(Some("Thinking"));
+        expander.style_context().add_class("thinking");
+        let label = Label::builder()
+            .label(thinking)
+            .selectable(true)
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        label.style_context().add_class("thinking-text");
+        expander.add(&label);
+        (expander, label)
+    }
+
+    // Collapsed by default, showing the arguments of the call and later its result.
+    fn tool_bubble(call: &ToolCall) -> gtk::Expander {
+        let bubble = gtk::Expander::new(Some(&format!("Tool: {}", call.name)));
+        bubble.style_context().add_class("tool-call");
+        let details = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let arguments = serde_json::to_string_pretty(&call.arguments()).unwrap_or_default();
+        let arguments_label = Self::new_label(&arguments, false, true);
+        arguments_label.style_context().add_class("tool-arguments");
+        details.pack_start(&arguments_label, false, false, 0);
+        bubble.add(&details);
+        bubble
+    }
+
+    // Long outputs, such as whole files, are cut short in the bubble.
+    fn set_tool_result(bubble: &impl IsA<gtk::Widget>, result: &ToolResult) {
+        let Some(details) = bubble
+            .dynamic_cast_ref::<gtk::Expander>()
+            .and_then(|bubble| bubble.child())
+            .and_then(|details| details.downcast::<gtk::Box>().ok())
+        else {
+            return;
+        };
+        let mut output: String = result.output.chars().take(2000).collect();
+        if output.len() < result.output.len() {
+            output.push('…');
+        }
+        let output_label = Self::new_label(&output, false, true);
+        output_label
+            .style_context()
+            .add_class(if result.is_error { "tool-error" } else { "tool-result" });
+        details.pack_start(&output_label, false, false, 0);
+        details.show_all();
+    }
+
+    // The sources an answer cites, numbered like the marks in the answer and linking to
+    // the source when it has a URL. Web searches made for the answer are listed first.
+    fn sources_label(sources: &[Source], searches: &[String]) -> Label {
+        let mut lines = Vec::new();
+        if !searches.is_empty() {
+            let searches: Vec<String> = searches.iter().map(|search| format!("“{}”", glib::markup_escape_text(search))).collect();
+            lines.push(format!("Searched for {}", searches.join(", ")));
+        }
+        lines.extend(sources.iter().enumerate().map(|(index, source)| {
+            let title = if source.title.is_empty() { &source.url } else { &source.title };
+            let title = glib::markup_escape_text(title);
+            if source.url.is_empty() {
+                format!("[{}] {}", index + 1, title)
+            } else {
+                format!("[{}] <a href=\"{}\">{}</a>", index + 1, glib::markup_escape_text(&source.url), title)
+            }
+        }));
+        let label = Label::builder().wrap(true).xalign(0.0).build();
+        label.set_markup(&lines.join("\n"));
+        label.style_context().add_class("sources");
+        label
+    }
+
+    // Why the provider stopped or refused to answer.
+    fn notice_label(notice: &str) -> Label {
+        let label = Label::builder().label(notice).wrap(true).xalign(0.0).build();
+        label.style_context().add_class("label-notice");
+        label
+    }
+
+    // Shows a finished answer with its sources, notice and usage.
+    fn show_answer(
+        answer: &ChatContent,
+        answer_box: &gtk::Box,
+        provider: &str,
+        config: &Config,
+        continues: &async_channel::Sender<Button>,
+    ) {
+        Self::set_answer(&answer.answer, answer_box, config);
+        if !answer.sources.is_empty() || !answer.searches.is_empty() {
+            answer_box.pack_start(&Self::sources_label(&answer.sources, &answer.searches), false, false, 0);
+        }
+        if !answer.notice.is_empty() {
+            answer_box.pack_start(&Self::notice_label(&answer.notice), false, false, 0);
+        }
+        if let Some(usage) = answer.usage {
+            let cost = config.price(provider, &answer.model).map(|price| price.cost(&usage));
+            answer_box.pack_start(&Self::answer_usage_label(&usage, cost), false, false, 0);
+        }
+        if answer.truncated {
+            answer_box.pack_start(&Self::continue_button(continues), false, false, 0);
+        }
+    }
+
+    // Asks for the rest of an answer that was cut off at the token limit.
+    fn continue_button(continues: &async_channel::Sender<Button>) -> Button {
+        let button = Button::builder()
+            .label("Continue")
+            .halign(gtk::Align::Start)
+            .build();
+        button.style_context().add_class("continue-button");
+        button.connect_clicked(clone!(@strong continues => move |button| {
+            continues.try_send(button.clone()).ok();
+        }));
+        button
+    }
+
+    // Marks an answer that was stopped before it was complete.
+    fn interrupted_label() -> Label {
+        let label = Label::new(Some("Interrupted"));
+        label.set_halign(gtk::Align::Start);
+        label.style_context().add_class("label-interrupted");
+        label
+    }
+
+    // Turns a pending answer into an error message, with a button to send the question again.
+    fn set_error(
+        error: &ConverseError,
+        pending: &Pending,
+        notebook: &gtk::Notebook,
+        entry: &Entry,
+        send_button: &Button,
+        attachments: &Attachments,
+    ) {
+        let answer_box = &pending.answer_box;
+        for child in answer_box.children() {
+            answer_box.remove(&child);
+        }
+        answer_box.style_context().remove_class("label-model");
+        answer_box.style_context().add_class("label-error");
+
+        let title_label = Label::new(None);
+        title_label.set_markup(&format!("<b>{}</b>", glib::markup_escape_text(&error.title())));
+        title_label.set_halign(gtk::Align::Start);
+        let error_label = Label::new(Some(&error.to_string()));
+        error_label.set_selectable(true);
+        error_label.set_wrap(true);
+        error_label.set_halign(gtk::Align::Start);
+        answer_box.pack_start(&title_label, false, false, 0);
+        answer_box.pack_start(&error_label, true, true, 0);
+        if !error.can_retry() {
+            return;
+        }
+
+        let retry_button = Button::builder()
+            .label("Retry")
+            .halign(gtk::Align::End)
+            .build();
+        retry_button.style_context().add_class("error-retry");
+        answer_box.pack_start(&retry_button, false, false, 0);
+
+        let question = pending.question.clone();
+        retry_button.connect_clicked(
+            clone!(@weak notebook, @weak entry, @weak send_button, @weak pending.question_box as question_box, @weak pending.tools_box as tools_box, @weak answer_box, @strong attachments => move |_| {
+                if !send_button.is_sensitive() {
+                    return;
+                }
+                let Some(chat_box) = answer_box.parent().and_then(|parent| parent.downcast::<gtk::Box>().ok()) else {
+                    return;
+                };
+                if let Some(scroll) = chat_box.parent().and_then(|viewport| viewport.parent()) {
+                    notebook.set_current_page(notebook.page_num(&scroll));
+                }
+                chat_box.remove(&question_box);
+                chat_box.remove(&tools_box);
+                chat_box.remove(&answer_box);
+                entry.set_text(&question.question);
+                for name in &question.images {
+                    attachments.add_saved(name.clone());
+                }
+                for source in &question.files {
+                    attachments.add_source(source.clone());
+                }
+                send_button.emit_clicked();
+            }),
+        );
+    }
+
+    // Shows what a pending answer is waiting for.
+    fn set_status(status: &str, answer_box: &gtk::Box) {
+        for child in answer_box.children() {
+            answer_box.remove(&child);
+        }
+        let status_label = Label::new(Some(status));
+        status_label.set_halign(gtk::Align::Start);
+        status_label.style_context().add_class("label-status");
+        answer_box.pack_start(&status_label, false, false, 0);
+    }
+
+    // Replaces the contents of a model answer with the newly formatted text.
+    fn set_answer(content: &str, answer_box: &gtk::Box, config: &Config) {
+        for child in answer_box.children() {
+            answer_box.remove(&child);
+        }
+        for block in md2pango(content, config) {
+            Self::model_response_format(block, answer_box);
+        }
+    }
+
     // Formats model responses based on code and non code block segments.
     fn model_response_format(block: parser::md2pango::FormattedCode, answer_box: &gtk::Box) {
         let label_model = Self::new_label(&block.string, false, block.is_code);
@@ -516,7 +2043,13 @@ impl UI {
 
 #[tokio::main]
 async fn main() {
-    let config = Arc::new(Config::new());
+    let args = Args::parse();
+    let config = Arc::new(Config::new(&args));
+    if let Some(Command::Usage) = args.command {
+        usage::print_report(&config);
+        return;
+    }
+
     let app = Application::builder()
         .application_id("com.github.vishruth-thimmaiah.converse")
         .build();
@@ -533,4 +2066,5 @@ async fn main() {
 
     let empty: Vec<String> = vec![];
     app.run_with_args(&empty);
+    mcp::shutdown();
 }
@@ -1,63 +1,965 @@
 pub mod claude;
 pub mod cohere;
 pub mod gemini;
+pub mod ollama;
 pub mod openai;
+mod retry;
+#[cfg(test)]
+mod test_server;
+pub mod tools;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{cmp::Reverse, fmt, ops::AddAssign, path::PathBuf, sync::Arc, time::Duration};
 
-use reqwest::{Error, StatusCode};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    Client, RequestBuilder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::parser::{cache::Cache, config::Config};
+use crate::{
+    error::ConverseError,
+    parser::{
+        cache::Cache,
+        config::{Config, ConfigGeneration, ConfigRetry},
+        files::{ContextFile, FileSource},
+    },
+};
 
-use self::{claude::Claude, cohere::Cohere, gemini::Gemini, openai::OpenAI};
+use self::{
+    claude::Claude,
+    cohere::Cohere,
+    gemini::Gemini,
+    ollama::Ollama,
+    openai::OpenAI,
+    tools::{shell::CommandRequest, Tool, ToolCall, ToolResult, ToolRound, ToolSpec},
+};
 
+// Reported while an answer is being generated.
+pub enum Progress<'a> {
+    Chunk(&'a str),
+    // Reasoning the model does before answering, kept apart from the answer.
+    Thinking(&'a str),
+    // The request failed and is sent again once the countdown reaches zero.
+    Retrying(Duration),
+    // The model called a tool, which is running until its result is reported.
+    ToolCall(&'a ToolCall),
+    ToolResult(&'a ToolResult),
+}
+
+#[derive(Clone, Default)]
 pub struct ChatContent {
     pub question: String,
     pub answer: String,
-    pub status: StatusCode,
+    pub interrupted: bool,
+    pub usage: Option<Usage>,
+    // The model that answered, as sent to the provider.
+    pub model: String,
+    // Names of the images attached to the question.
+    pub images: Vec<String>,
+    // Files attached to the question, and their contents when it was sent.
+    pub files: Vec<FileSource>,
+    pub context: Vec<ContextFile>,
+    // What the model reasoned before answering, shown apart from the answer.
+    pub thinking: String,
+    // The thinking of the response being streamed, which Claude needs back with tool results.
+    pub thinking_blocks: Vec<ThinkingBlock>,
+    // Calls in the response being streamed, run once it is complete.
+    pub tool_calls: Vec<ToolCall>,
+    // Calls the model made before its final answer.
+    pub tool_rounds: Vec<ToolRound>,
+    // What the answer cites, numbered from 1 in this order.
+    pub sources: Vec<Source>,
+    // Where the cited text ends in the response being streamed, marked once it is complete.
+    pub citations: Vec<Citation>,
+    // What the provider searched the web for while answering.
+    pub searches: Vec<String>,
+    // Search results the provider sent before citing them by id.
+    pub documents: Vec<Source>,
+    // Why the answer stopped early or is missing, shown under it.
+    pub notice: String,
+    // The provider refused the question or the answer. Blocked turns are kept
+    // out of the history sent with later questions.
+    pub blocked: bool,
+    // Why the model stopped, as the provider reported it.
+    pub stop_reason: String,
+    // The answer reached the output token limit, so it can be continued.
+    pub truncated: bool,
+}
+
+impl ChatContent {
+    // Cites `source` for the text ending at `end`, numbering sources in the order they are first cited.
+    pub fn cite(&mut self, end: usize, source: Source) {
+        let index = match self.sources.iter().position(|cited| cited.same_as(&source)) {
+            Some(index) => index,
+            None => {
+                self.sources.push(source);
+                self.sources.len() - 1
+            }
+        };
+        match self
+            .citations
+            .iter_mut()
+            .find(|citation| citation.end == end)
+        {
+            Some(citation) if citation.sources.contains(&index) => {}
+            Some(citation) => citation.sources.push(index),
+            None => self.citations.push(Citation {
+                end,
+                sources: vec![index],
+            }),
+        }
+    }
+
+    // Appends the continuation of a truncated answer. How the answer ends is taken from it,
+    // unless it was stopped, which leaves the answer truncated so it can be continued again.
+    pub fn extend(&mut self, continuation: &ChatContent) {
+        self.answer.push_str(&continuation.answer);
+        if !self.thinking.is_empty() && !continuation.thinking.is_empty() {
+            self.thinking.push_str("\n\n");
+        }
+        self.thinking.push_str(&continuation.thinking);
+        if let Some(usage) = continuation.usage {
+            *self.usage.get_or_insert_with(Usage::default) += usage;
+        }
+        self.tool_rounds
+            .extend(continuation.tool_rounds.iter().cloned());
+        self.sources.extend(continuation.sources.iter().cloned());
+        self.searches.extend(continuation.searches.iter().cloned());
+        self.blocked |= continuation.blocked;
+        if continuation.interrupted {
+            self.interrupted = true;
+            return;
+        }
+        self.notice = continuation.notice.clone();
+        self.stop_reason = continuation.stop_reason.clone();
+        self.truncated = continuation.truncated;
+        self.interrupted = false;
+    }
 }
 
-pub fn get_models(config: &Arc<Config>) -> Vec<String> {
-    let mut models = Vec::new();
-    models.push((config.gemini.use_model, "Gemini"));
-    models.push((config.cohere.use_model, "Cohere"));
-    models.push((config.claude.use_model, "Claude"));
-    models.push((config.openai.use_model, "OpenAI"));
+// A document or web page the answer is based on, listed under it as a footnote.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Source {
+    // The provider's id for it, only used while the answer is streamed.
+    #[serde(skip)]
+    pub id: String,
+    pub title: String,
+    pub url: String,
+}
+
+impl Source {
+    // Search results often cite several snippets of the same page.
+    fn same_as(&self, other: &Source) -> bool {
+        if self.url.is_empty() {
+            self.id == other.id && self.title == other.title
+        } else {
+            self.url == other.url
+        }
+    }
+}
+
+// A block of reasoning, with the signature Claude checks when it is sent back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThinkingBlock {
+    pub text: String,
+    pub signature: String,
+    // Reasoning Claude encrypted instead of showing, sent back as it is.
+    pub redacted: String,
+}
+
+// Text cited from sources, ending `end` characters into the answer.
+#[derive(Debug, Clone)]
+pub struct Citation {
+    pub end: usize,
+    pub sources: Vec<usize>,
+}
+
+// Puts a footnote mark like [1] after each cited piece of text. Sources are numbered
+// after the `first` ones, which an earlier part of the answer cites.
+fn mark_citations(answer: &str, citations: &[Citation], first: usize) -> String {
+    let mut citations = citations.to_vec();
+    citations.sort_by_key(|citation| citation.end);
+    let mut marked = String::new();
+    let mut citations = citations.iter().peekable();
+    for (index, c) in answer.chars().enumerate() {
+        while let Some(citation) = citations.next_if(|citation| citation.end <= index) {
+            marked.push_str(&footnote_marks(citation, first));
+        }
+        marked.push(c);
+    }
+    for citation in citations {
+        marked.push_str(&footnote_marks(citation, first));
+    }
+    marked
+}
 
-    // sort models by use_model and return as Vec<String>, where higher use_model is first.
-    models.sort_by(|a, b| b.0.cmp(&a.0));
-    let sorted_models: Vec<String> = models
+fn footnote_marks(citation: &Citation, first: usize) -> String {
+    citation
+        .sources
         .iter()
-        .filter_map(|(priority, model)| {
-            if priority != &0 {
-                Some(model.to_string())
-            } else {
-                None
-            }
+        .map(|index| format!("[{}]", first + index + 1))
+        .collect()
+}
+
+// An image attached to a question, ready to be sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub mime_type: String,
+    // Base64 encoded.
+    pub data: String,
+}
+
+impl Image {
+    pub fn new(mime_type: &str, bytes: &[u8]) -> Self {
+        Self {
+            mime_type: mime_type.to_string(),
+            data: STANDARD.encode(bytes),
+        }
+    }
+
+    // Only PNG and JPEG images are accepted by every provider.
+    pub fn mime_type_of(bytes: &[u8]) -> Option<&'static str> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some("image/png")
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some("image/jpeg")
+        } else {
+            None
+        }
+    }
+
+    fn data_uri(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.data)
+    }
+}
+
+// Tokens billed for one answer, as reported by the provider.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    // Prompt tokens written to and read from Claude's cache, which aren't in input_tokens.
+    pub cache_creation_tokens: u64,
+    pub cache_read_tokens: u64,
+}
+
+impl Usage {
+    // Reads a provider's usage object, given its names for the two counts.
+    fn from_json(usage: &serde_json::Value, input: &str, output: &str) -> Option<Self> {
+        usage.is_object().then(|| Self {
+            input_tokens: usage[input].as_u64().unwrap_or_default(),
+            output_tokens: usage[output].as_u64().unwrap_or_default(),
+            ..Default::default()
         })
+    }
+
+    pub fn total(&self) -> u64 {
+        self.input_tokens + self.cache_creation_tokens + self.cache_read_tokens + self.output_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_tokens += other.cache_creation_tokens;
+        self.cache_read_tokens += other.cache_read_tokens;
+    }
+}
+
+// A provider and one of its models, as picked in the UI and stored in history files.
+// An empty model means the provider's default one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    pub provider: String,
+    pub model: String,
+}
+
+// Optional features a provider supports, shown next to the model selector.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub web_search: bool,
+    pub max_tokens: bool,
+    pub images: bool,
+    pub tools: bool,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut features = Vec::new();
+        if self.web_search {
+            features.push("web search");
+        }
+        if self.max_tokens {
+            features.push("token limit");
+        }
+        if self.images {
+            features.push("images");
+        }
+        if self.tools {
+            features.push("tools");
+        }
+        if features.is_empty() {
+            write!(f, "chat")
+        } else {
+            write!(f, "chat, {}", features.join(", "))
+        }
+    }
+}
+
+/// A chat backend. Adding a new backend only requires implementing this trait
+/// and registering it in [`registry`].
+pub trait Provider: Send + Sync {
+    /// Name shown in the model selector and stored in history files.
+    fn name(&self) -> &str;
+
+    /// The `use_model` priority from the config. Providers with 0 are disabled.
+    fn priority(&self) -> u32;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// The configured model. When empty, the first model from [`list_models`]
+    /// is used instead, unless [`Self::requires_model`] is false.
+    fn model(&self) -> &str;
+
+    /// Whether a model has to be sent. If not, the provider picks its own
+    /// default when no model is configured or selected.
+    fn requires_model(&self) -> bool {
+        true
+    }
+
+    /// Models that can be picked in the UI, starting with the default one.
+    fn models(&self) -> Vec<String> {
+        model_choices(self.model(), &[])
+    }
+
+    /// The sampling parameters from the provider's config section.
+    fn generation(&self) -> &ConfigGeneration;
+
+    /// The system prompt from the provider's config section.
+    fn system_prompt(&self) -> &str;
+
+    /// Builds the request body from the system prompt, the configured
+    /// conversation input, the previous chat, the new query and the sampling
+    /// parameters to use. An empty system prompt is not sent. The query is a
+    /// message like the ones in the chat, read with [`message_text`] and
+    /// [`message_images`]. Once the model calls tools, the calls and their
+    /// results follow it, read with [`tool_rounds`]. `tools` is empty when
+    /// none are enabled or the provider doesn't support them.
+    fn create_query(
+        &self,
+        model: &str,
+        query: &serde_json::Value,
+        init_input: &serde_json::Value,
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError>;
+
+    /// Sets the url and headers needed to stream the response to `data`.
+    fn build_request(
+        &self,
+        client: &Client,
+        model: &str,
+        data: &serde_json::Value,
+    ) -> RequestBuilder;
+
+    /// Handles one line of the streamed response body, returning the text that
+    /// should be appended to the answer.
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError>;
+
+    /// Extracts the message from an error response body.
+    fn parse_error(&self, response: &str) -> Option<String>;
+
+    /// How often to retry rate limited or failed requests.
+    fn retry(&self) -> &ConfigRetry;
+
+    /// How long the provider asked to wait before the next attempt.
+    fn retry_after(&self, headers: &HeaderMap) -> Option<Duration> {
+        retry::retry_after(headers)
+    }
+
+    /// Request for the models the provider can serve, if it can list them.
+    fn model_list_request(&self, _client: &Client) -> Option<RequestBuilder> {
+        None
+    }
+
+    /// Extracts the model names from the response to [`Self::model_list_request`].
+    fn parse_model_list(&self, _response: &str) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// An answer stopped before any of it arrived, which providers like Claude reject.
+fn is_unanswered(item: Option<&serde_json::Value>) -> bool {
+    item.is_some_and(|item| {
+        item["interrupted"] == true && item["text"].as_str().unwrap_or_default().is_empty()
+    })
+}
+
+// The configured conversation input followed by the previous chat, with the
+// role of each message. Unanswered questions are left out along with their answer.
+fn chat_history<'a>(
+    conversation_input: &'a serde_json::Value,
+    init_input: &'a serde_json::Value,
+) -> Result<Vec<(&'a str, &'a serde_json::Value)>, ConverseError> {
+    let conversation_input = conversation_input
+        .as_array()
+        .ok_or_else(|| ConverseError::Config("conversation_input must be a list.".to_string()))?;
+    let init_input = init_input.as_array().map(Vec::as_slice).unwrap_or_default();
+    conversation_input
+        .iter()
+        .chain(
+            init_input
+                .iter()
+                .enumerate()
+                .filter(|(index, item)| {
+                    item["blocked"] != true
+                        && !is_unanswered(Some(item))
+                        && !is_unanswered(init_input.get(index + 1))
+                })
+                .map(|(_, item)| item),
+        )
+        .map(|item| match item["role"].as_str() {
+            Some(role) => Ok((role, item)),
+            None => Err(ConverseError::Config(format!(
+                "Every conversation_input entry needs a role, found {}.",
+                item
+            ))),
+        })
+        .collect()
+}
+
+// The configured model followed by the others listed in `models`.
+fn model_choices(model: &str, models: &[String]) -> Vec<String> {
+    let mut choices: Vec<String> = Vec::new();
+    for model in std::iter::once(model).chain(models.iter().map(String::as_str)) {
+        if !model.is_empty() && !choices.iter().any(|choice| choice == model) {
+            choices.push(model.to_string());
+        }
+    }
+    choices
+}
+
+// The text of a message, after the contents of the files attached to it.
+fn message_text(item: &serde_json::Value) -> String {
+    let context: Vec<ContextFile> =
+        serde_json::from_value(item["context"].clone()).unwrap_or_default();
+    context
+        .iter()
+        .map(ContextFile::block)
+        .chain(std::iter::once(
+            item["text"].as_str().unwrap_or_default().to_string(),
+        ))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// Images attached to a message, once loaded with `Cache::load_images`.
+fn message_images(item: &serde_json::Value) -> Vec<Image> {
+    serde_json::from_value(item["images"].clone()).unwrap_or_default()
+}
+
+// Tool calls made while answering the query, with their results.
+fn tool_rounds(query: &serde_json::Value) -> Vec<ToolRound> {
+    serde_json::from_value(query["tool_rounds"].clone()).unwrap_or_default()
+}
+
+// The sampling parameters that are set, under the names a provider uses for
+// temperature, top_p, max output tokens, stop sequences and seed. Parameters
+// with an empty name are not supported by the provider.
+fn generation_fields(
+    generation: &ConfigGeneration,
+    names: [&str; 5],
+) -> serde_json::Map<String, serde_json::Value> {
+    let values = [
+        generation.temperature.map(|temperature| json!(temperature)),
+        generation.top_p.map(|top_p| json!(top_p)),
+        generation
+            .max_output_tokens
+            .map(|max_tokens| json!(max_tokens)),
+        (!generation.stop.is_empty()).then(|| json!(generation.stop)),
+        generation.seed.map(|seed| json!(seed)),
+    ];
+    names
+        .into_iter()
+        .zip(values)
+        .filter(|(name, _)| !name.is_empty())
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect()
+}
+
+// Reads `error.message` from the error bodies used by most providers.
+fn error_message(response: &str) -> Option<String> {
+    let response_content: serde_json::Value = serde_json::from_str(response).ok()?;
+    match &response_content["error"] {
+        serde_json::Value::String(message) => Some(message.to_string()),
+        error => error["message"].as_str().map(str::to_string),
+    }
+}
+
+// Returns the payload of a server-sent event `data:` line.
+fn sse_data(line: &str) -> Option<&str> {
+    line.strip_prefix("data:")
+        .map(str::trim)
+        .filter(|data| *data != "[DONE]")
+}
+
+// Every provider converse knows about, enabled or not.
+fn registry(config: &Config) -> Vec<Box<dyn Provider>> {
+    let mut providers: Vec<Box<dyn Provider>> = vec![
+        Box::new(Gemini::new(&config.gemini)),
+        Box::new(Cohere::new(&config.cohere)),
+        Box::new(Claude::new(&config.claude)),
+        Box::new(OpenAI::new(&config.openai)),
+        Box::new(Ollama::new(&config.ollama)),
+    ];
+    for custom in &config.custom {
+        providers.push(Box::new(OpenAI::custom(custom)));
+    }
+    providers
+}
+
+// Enabled providers, where higher use_model is first.
+pub fn providers(config: &Config) -> Vec<Box<dyn Provider>> {
+    let mut providers: Vec<Box<dyn Provider>> = registry(config)
+        .into_iter()
+        .filter(|provider| provider.priority() != 0)
         .collect();
+    providers.sort_by_key(|provider| Reverse(provider.priority()));
+    providers
+}
+
+pub fn find_provider(config: &Config, name: &str) -> Option<Box<dyn Provider>> {
+    providers(config)
+        .into_iter()
+        .find(|provider| provider.name() == name)
+}
+
+pub fn get_models(config: &Config) -> Vec<String> {
+    providers(config)
+        .iter()
+        .map(|provider| provider.name().to_string())
+        .collect()
+}
+
+pub async fn list_models(provider: &dyn Provider) -> Result<Vec<String>, ConverseError> {
+    let Some(request) = provider.model_list_request(&Client::new()) else {
+        return Ok(Vec::new());
+    };
+    let response = request.send().await?;
+    let status = response.status();
+    let response = response.text().await?;
+    if !status.is_success() {
+        let message = provider.parse_error(&response);
+        return Err(ConverseError::Status { status, message });
+    }
+    Ok(provider.parse_model_list(&response))
+}
+
+// Sends the query, then runs the tools the model calls and sends their results
+// back, until it answers without calling any.
+async fn request(
+    provider: &dyn Provider,
+    model: &str,
+    query: &serde_json::Value,
+    conversation: &serde_json::Value,
+    tools: &[Box<dyn Tool>],
+    max_rounds: u32,
+    on_update: impl Fn(Progress),
+) -> Result<ChatContent, ConverseError> {
+    if !message_images(query).is_empty() && !provider.capabilities().images {
+        return Err(ConverseError::Config(format!(
+            "{} can't read images.",
+            provider.name()
+        )));
+    }
+    let model = if !model.is_empty() {
+        model.to_string()
+    } else if provider.model().is_empty() && provider.requires_model() {
+        list_models(provider)
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    } else {
+        provider.model().to_string()
+    };
+    let generation = provider
+        .generation()
+        .merged(&Cache::generation(conversation));
+    let system_prompt = Cache::system_prompt(conversation);
+    let system_prompt = if system_prompt.is_empty() {
+        provider.system_prompt()
+    } else {
+        system_prompt
+    };
+    let specs: Vec<ToolSpec> = if provider.capabilities().tools {
+        tools.iter().map(|tool| tool.spec().clone()).collect()
+    } else {
+        Vec::new()
+    };
+
+    let client = Client::new();
+    let mut query = query.clone();
+    let mut content = ChatContent {
+        question: query["text"].as_str().unwrap_or_default().to_string(),
+        model: model.clone(),
+        ..Default::default()
+    };
+    let mut usage: Option<Usage> = None;
+    let mut citations = Vec::new();
+    loop {
+        let data = provider.create_query(
+            &model,
+            &query,
+            &conversation["chat"],
+            system_prompt,
+            &generation,
+            &specs,
+        )?;
+        // Only the last response decides how the answer ends.
+        content.stop_reason.clear();
+        content.truncated = false;
+        let round_start = content.answer.len();
+        stream(provider, &client, &model, &data, &mut content, &on_update).await?;
+        // Citations count from the start of their own response.
+        let offset = content.answer[..round_start].chars().count();
+        for mut citation in std::mem::take(&mut content.citations) {
+            citation.end += offset;
+            citations.push(citation);
+        }
+        // Every response reports the usage of its own request.
+        if let Some(round_usage) = content.usage.take() {
+            *usage.get_or_insert_with(Usage::default) += round_usage;
+        }
+
+        let calls = std::mem::take(&mut content.tool_calls);
+        if calls.is_empty() {
+            break;
+        }
+        if content.tool_rounds.len() as u32 >= max_rounds {
+            return Err(ConverseError::Provider(format!(
+                "The model was still calling tools after {} rounds.",
+                max_rounds
+            )));
+        }
+        let mut results = Vec::new();
+        for call in &calls {
+            on_update(Progress::ToolCall(call));
+            let result = tools::call(tools, call).await;
+            on_update(Progress::ToolResult(&result));
+            results.push(result);
+        }
+        let text = content.answer[round_start..].to_string();
+        if !text.is_empty() {
+            content.answer.push_str("\n\n");
+            on_update(Progress::Chunk("\n\n"));
+        }
+        content.tool_rounds.push(ToolRound {
+            text,
+            thinking: std::mem::take(&mut content.thinking_blocks),
+            calls,
+            results,
+        });
+        query["tool_rounds"] = serde_json::to_value(&content.tool_rounds)?;
+    }
+    content.usage = usage;
+    content.citations = citations;
+    if content.truncated && content.notice.is_empty() {
+        content.notice = "The answer was cut off at the output token limit.".to_string();
+    }
+    Ok(content)
+}
+
+// Sends one request, retrying it as configured, and streams the response into `content`.
+async fn stream(
+    provider: &dyn Provider,
+    client: &Client,
+    model: &str,
+    data: &serde_json::Value,
+    content: &mut ChatContent,
+    on_update: &impl Fn(Progress),
+) -> Result<(), ConverseError> {
+    let policy = provider.retry();
+    let mut attempt = 1;
+    let mut response = loop {
+        let response = provider.build_request(client, model, data).send().await?;
+        let status = response.status();
+        if status.is_success() {
+            break response;
+        }
+        let retry_after = provider.retry_after(response.headers());
+        let message = provider.parse_error(&response.text().await?);
+        let delay = retry_after.unwrap_or_else(|| retry::backoff(policy, attempt));
+
+        // A provider asking for more than max_delay gets its error shown rather than a long
+        // silent wait, leaving it to the user to send again later.
+        if attempt >= policy.max_attempts
+            || !retry::is_retryable(status)
+            || delay.as_secs_f64() > policy.max_delay
+        {
+            return Err(ConverseError::Status { status, message });
+        }
 
-    sorted_models
+        let mut remaining = delay;
+        while !remaining.is_zero() {
+            on_update(Progress::Retrying(remaining));
+            let step = remaining.min(Duration::from_secs(1));
+            tokio::time::sleep(step).await;
+            remaining -= step;
+        }
+        attempt += 1;
+    };
+    // Proxies and captive portals answer with a web page instead of the stream.
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if is_html {
+        return Err(ConverseError::Decode(format!(
+            "Expected a response from {}, but received a web page.",
+            provider.name()
+        )));
+    }
+
+    let mut process_line = |line: &[u8]| -> Result<(), ConverseError> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let thought = content.thinking.len();
+        let text = provider.process_stream(line, content)?;
+        if content.thinking.len() > thought {
+            on_update(Progress::Thinking(&content.thinking[thought..]));
+        }
+        if let Some(text) = text.filter(|text| !text.is_empty()) {
+            content.answer.push_str(&text);
+            on_update(Progress::Chunk(&text));
+        }
+        Ok(())
+    };
+
+    // Chunks can end in the middle of a line, so only complete lines are processed.
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            process_line(&line)?;
+        }
+    }
+    process_line(&buffer)?;
+
+    Ok(())
 }
 
+// Streams the answer to `question`, calling `on_update` with each new piece of
+// text. The conversation is only cached once the whole answer has arrived.
 pub async fn select_model(
-    combobox_selection: &str,
-    entry_text: &str,
+    selection: &Selection,
+    question: &ChatContent,
     config: Arc<Config>,
     file: PathBuf,
-) -> Result<ChatContent, Error> {
-    let init_input = Cache::read(&file);
-    let result = match combobox_selection {
-        "Gemini" => Gemini::request(&entry_text, &config.gemini, &init_input["chat"]).await,
-        "Cohere" => Cohere::request(&entry_text, &config.cohere, &init_input["chat"]).await,
-        "Claude" => Claude::request(&entry_text, &config.claude, &init_input["chat"]).await,
-        "OpenAI" => OpenAI::request(&entry_text, &config.openai, &init_input["chat"]).await,
-        _ => unreachable!(),
-    };
-    if let Ok(output) = &result {
-        if output.status.is_success() {
-            Cache::update_conversation(file, output, combobox_selection);
+    approvals: &async_channel::Sender<CommandRequest>,
+    on_update: impl Fn(Progress),
+) -> Result<ChatContent, ConverseError> {
+    let provider = find_provider(&config, &selection.provider)
+        .ok_or_else(|| ConverseError::Config(format!("{} is not enabled.", selection.provider)))?;
+    let history_path = &config.general.history_path;
+    let mut conversation = Cache::read(&file);
+    Cache::load_images(history_path, &mut conversation);
+    let mut query = Cache::question(question)?;
+    let images = question
+        .images
+        .iter()
+        .map(|name| Cache::load_image(history_path, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    query["images"] = serde_json::to_value(images)?;
+    let mut output = request(
+        provider.as_ref(),
+        &selection.model,
+        &query,
+        &conversation,
+        &tools::registry(&config, approvals),
+        config.tools.max_rounds,
+        on_update,
+    )
+    .await?;
+    output.answer = mark_citations(&output.answer, &output.citations, 0);
+    output.images = question.images.clone();
+    output.files = question.files.clone();
+    output.context = question.context.clone();
+    Cache::update_conversation(file, &output, selection)?;
+    Ok(output)
+}
+
+// Asks the model to go on with the last answer of the conversation, which was cut
+// off at the token limit. The continuation is appended to that answer in the
+// history, and returned on its own.
+pub async fn continue_answer(
+    selection: &Selection,
+    config: Arc<Config>,
+    file: PathBuf,
+    approvals: &async_channel::Sender<CommandRequest>,
+    on_update: impl Fn(Progress),
+) -> Result<ChatContent, ConverseError> {
+    let provider = find_provider(&config, &selection.provider)
+        .ok_or_else(|| ConverseError::Config(format!("{} is not enabled.", selection.provider)))?;
+    let mut conversation = Cache::read(&file);
+    Cache::load_images(&config.general.history_path, &mut conversation);
+    let previous = conversation["chat"]
+        .as_array()
+        .and_then(|chat| chat.last())
+        .filter(|item| item["role"] == "model" && item["truncated"] == true)
+        .map(Cache::answer)
+        .ok_or_else(|| ConverseError::Config("There is no answer to continue.".to_string()))?;
+    let query = json!({
+        "role": "user",
+        "text": "Continue exactly where your last answer stopped, without repeating any of it."
+    });
+    let mut output = request(
+        provider.as_ref(),
+        &selection.model,
+        &query,
+        &conversation,
+        &tools::registry(&config, approvals),
+        config.tools.max_rounds,
+        on_update,
+    )
+    .await?;
+    output.answer = mark_citations(&output.answer, &output.citations, previous.sources.len());
+    let mut answer = previous;
+    answer.extend(&output);
+    Cache::replace_answer(file, &answer)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn citation(end: usize, sources: &[usize]) -> Citation {
+        Citation {
+            end,
+            sources: sources.to_vec(),
         }
     }
-    result
+
+    #[test]
+    fn history_leaves_out_unanswered_questions() {
+        let input = json!([{"role": "user", "text": "Be brief."}]);
+        let chat = json!([
+            {"role": "user", "text": "First"},
+            {"role": "model", "text": "", "interrupted": true},
+            {"role": "user", "text": "Second"},
+            {"role": "model", "text": "Partly", "interrupted": true},
+            {"role": "user", "text": "Third"},
+            {"role": "model", "text": "Done"}
+        ]);
+        let history = chat_history(&input, &chat).unwrap();
+        let texts: Vec<&str> = history
+            .iter()
+            .map(|(_, item)| item["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, ["Be brief.", "Second", "Partly", "Third", "Done"]);
+    }
+
+    fn truncated() -> ChatContent {
+        ChatContent {
+            answer: "The first half".to_string(),
+            notice: "The answer reached the token limit.".to_string(),
+            stop_reason: "max_tokens".to_string(),
+            truncated: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn continuation_decides_how_the_answer_ends() {
+        let mut answer = truncated();
+        answer.extend(&ChatContent {
+            answer: " and the rest.".to_string(),
+            stop_reason: "end_turn".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(answer.answer, "The first half and the rest.");
+        assert_eq!(answer.stop_reason, "end_turn");
+        assert_eq!(answer.notice, "");
+        assert!(!answer.truncated);
+        assert!(!answer.interrupted);
+    }
+
+    #[test]
+    fn interrupted_continuation_stays_truncated() {
+        let mut answer = truncated();
+        answer.extend(&ChatContent {
+            answer: " and some".to_string(),
+            interrupted: true,
+            ..Default::default()
+        });
+        assert_eq!(answer.answer, "The first half and some");
+        assert_eq!(answer.stop_reason, "max_tokens");
+        assert_eq!(answer.notice, "The answer reached the token limit.");
+        assert!(answer.truncated);
+        assert!(answer.interrupted);
+
+        // Continuing again finishes it.
+        answer.extend(&ChatContent {
+            answer: " more.".to_string(),
+            ..Default::default()
+        });
+        assert!(!answer.truncated);
+        assert!(!answer.interrupted);
+    }
+
+    #[test]
+    fn marks_citations_after_the_cited_text() {
+        let citations = [citation(5, &[0]), citation(11, &[1, 2])];
+        assert_eq!(
+            mark_citations("Hello world.", &citations, 0),
+            "Hello[1] world[2][3]."
+        );
+        assert_eq!(
+            mark_citations("Hello world.", &citations[..1], 2),
+            "Hello[3] world."
+        );
+    }
+
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        let citations = [citation(4, &[0]), citation(7, &[1])];
+        assert_eq!(
+            mark_citations("Grüß dich 🙂!", &citations, 0),
+            "Grüß[1] di[2]ch 🙂!"
+        );
+        assert_eq!(
+            mark_citations("🙂🙂🙂", &[citation(2, &[0])], 0),
+            "🙂🙂[1]🙂"
+        );
+    }
+
+    #[test]
+    fn keeps_overlapping_and_unordered_citations() {
+        let citations = [citation(3, &[1]), citation(3, &[0]), citation(1, &[2])];
+        assert_eq!(mark_citations("abcd", &citations, 0), "a[3]bc[2][1]d");
+    }
+
+    #[test]
+    fn puts_out_of_range_citations_at_the_end() {
+        let citations = [citation(100, &[0]), citation(4, &[1])];
+        assert_eq!(mark_citations("café", &citations, 0), "café[2][1]");
+        assert_eq!(mark_citations("", &citations, 0), "[2][1]");
+    }
 }
@@ -1,98 +1,478 @@
-use reqwest::{Client, Error, StatusCode};
+use std::time::Duration;
+
+use reqwest::{header::HeaderMap, Client, RequestBuilder};
 use serde_json::json;
 
-use crate::parser::config::ConfigClaude;
+use crate::{
+    error::ConverseError,
+    parser::config::{ConfigClaude, ConfigGeneration, ConfigRetry},
+};
+
+use super::{
+    chat_history, error_message, generation_fields, message_images, message_text, model_choices,
+    retry, sse_data, tool_rounds,
+    tools::{ToolCall, ToolSpec},
+    Capabilities, ChatContent, Provider, ThinkingBlock, Usage,
+};
+
+// The longest answer current Claude models give, thinking included.
+const MAX_OUTPUT_TOKENS: u64 = 64000;
 
-use super::ChatContent;
+// Images are placed before the text, as recommended by Anthropic.
+fn content(item: &serde_json::Value) -> serde_json::Value {
+    let text = message_text(item);
+    let images = message_images(item);
+    if images.is_empty() {
+        return json!(text);
+    }
+    let mut blocks: Vec<serde_json::Value> = images
+        .iter()
+        .map(|image| {
+            json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": image.mime_type, "data": image.data}
+            })
+        })
+        .collect();
+    blocks.push(json!({"type": "text", "text": text}));
+    json!(blocks)
+}
+
+// Marks the end of a part of the prompt that doesn't change, so Claude caches everything
+// up to it. Empty text can't be marked.
+fn cache_breakpoint(content: &mut serde_json::Value) {
+    if let Some(text) = content.as_str().filter(|text| !text.is_empty()) {
+        *content = json!([{"type": "text", "text": text}]);
+    }
+    if let Some(block) = content.as_array_mut().and_then(|blocks| blocks.last_mut()) {
+        block["cache_control"] = json!({"type": "ephemeral"});
+    }
+}
 
-pub struct Claude {}
+pub struct Claude {
+    config: ConfigClaude,
+}
 
-const URL: &'static str = "https://api.anthropic.com/v1/messages";
+const URL: &str = "https://api.anthropic.com/v1/messages";
+const MODELS_URL: &str = "https://api.anthropic.com/v1/models?limit=1000";
 
 impl Claude {
-    pub async fn request(
-        query: &str,
-        config: &ConfigClaude,
-        init_input: &serde_json::Value,
-    ) -> Result<ChatContent, Error> {
-        let url = format!("{}", URL);
-        let mut conversation = Self::create_query(
-            config.max_tokens,
-            &config.model,
-            &config.conversation_input,
-            init_input,
-        );
+    pub fn new(config: &ConfigClaude) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
 
-        conversation["messages"]
-            .as_array_mut()
-            .unwrap()
-            .push(json!({ "role": "user", "content": query }));
+impl Provider for Claude {
+    fn name(&self) -> &str {
+        "Claude"
+    }
 
-        let (response, status) =
-            Self::send_request(&url, &conversation, &config.api, &config.anthropic_version).await?;
-        let result = Self::process_response(query, &response, status)?;
+    fn priority(&self) -> u32 {
+        self.config.use_model
+    }
 
-        Ok(result)
+    fn retry(&self) -> &ConfigRetry {
+        &self.config.retry
     }
-    async fn send_request(
-        url: &str,
-        data: &serde_json::Value,
-        api: &str,
-        anthropic_version: &str,
-    ) -> Result<(String, StatusCode), Error> {
-        let response = Client::new()
-            .post(url)
-            .header("x-api-key", api)
-            .header("anthropic-version", anthropic_version)
-            .header("Content-Type", "application/json")
-            .json(&data)
-            .send()
-            .await?;
-        let status = response.status();
-        let response = response.text().await?;
-        Ok((response, status))
-    }
-
-    fn process_response(
-        query: &str,
-        response: &str,
-        status: StatusCode,
-    ) -> Result<ChatContent, Error> {
-        let response_content: serde_json::Value = serde_json::from_str(response).unwrap();
-        let answer = response_content
-            .pointer("/content/0/text")
-            .and_then(|val| val.as_str())
-            .unwrap_or("");
-        let result = ChatContent {
-            question: query.to_string(),
-            answer: answer.to_string(),
-            status,
-        };
-        Ok(result)
+
+    fn generation(&self) -> &ConfigGeneration {
+        &self.config.generation
+    }
+
+    fn system_prompt(&self) -> &str {
+        &self.config.system_prompt
+    }
+
+    // Without a retry-after header, wait for the exhausted ratelimits to reset.
+    fn retry_after(&self, headers: &HeaderMap) -> Option<Duration> {
+        retry::retry_after(headers).or_else(|| {
+            ["requests", "tokens", "input-tokens", "output-tokens"]
+                .iter()
+                .filter(|limit| {
+                    headers
+                        .get(format!("anthropic-ratelimit-{}-remaining", limit))
+                        .is_some_and(|remaining| remaining == "0")
+                })
+                .filter_map(|limit| {
+                    retry::until_header_time(
+                        headers,
+                        &format!("anthropic-ratelimit-{}-reset", limit),
+                    )
+                })
+                .max()
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_tokens: true,
+            images: true,
+            tools: true,
+            ..Default::default()
+        }
+    }
+
+    fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn models(&self) -> Vec<String> {
+        model_choices(&self.config.model, &self.config.models)
     }
 
     fn create_query(
-        tokens: u32,
+        &self,
         model: &str,
-        conversation_input: &serde_json::Value,
+        query: &serde_json::Value,
         init_input: &serde_json::Value,
-    ) -> serde_json::Value {
-        let mut template = json!({"model": model, "max_tokens": tokens, "messages": []});
-
-        for item in conversation_input.as_array().unwrap() {
-            template["messages"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({ "role": item["role"].as_str().unwrap().replace("model", "assistant"), "content": item["text"]}))
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut template = json!({"model": model, "max_tokens": self.config.max_tokens, "stream": true, "messages": []});
+        if !system_prompt.is_empty() {
+            template["system"] = json!(system_prompt);
+            if self.config.prompt_caching {
+                cache_breakpoint(&mut template["system"]);
+            }
         }
+        let messages = template["messages"].as_array_mut().unwrap();
 
-        for item in init_input.as_array().unwrap() {
-            template["messages"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({ "role": item["role"].as_str().unwrap().replace("model", "assistant"), "content": item["text"]}))
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            let role = role.replace("model", "assistant");
+            messages.push(json!({"role": role, "content": content(item)}));
+        }
+        // The history up to the new prompt is sent again unchanged with the next one.
+        if self.config.prompt_caching {
+            if let Some(message) = messages.last_mut() {
+                cache_breakpoint(&mut message["content"]);
+            }
         }
 
-        template
+        messages.push(json!({ "role": "user", "content": content(query) }));
+        let rounds = tool_rounds(query);
+        for round in &rounds {
+            let mut blocks = Vec::new();
+            // Thinking has to come first, as Claude sent it.
+            blocks.extend(round.thinking.iter().map(|block| {
+                if block.redacted.is_empty() {
+                    json!({"type": "thinking", "thinking": block.text, "signature": block.signature})
+                } else {
+                    json!({"type": "redacted_thinking", "data": block.redacted})
+                }
+            }));
+            if !round.text.is_empty() {
+                blocks.push(json!({"type": "text", "text": round.text}));
+            }
+            blocks.extend(round.calls.iter().map(|call| {
+                json!({"type": "tool_use", "id": call.id, "name": call.name, "input": call.arguments()})
+            }));
+            messages.push(json!({"role": "assistant", "content": blocks}));
+            let results: Vec<serde_json::Value> = round
+                .results
+                .iter()
+                .map(|result| {
+                    json!({
+                        "type": "tool_result",
+                        "tool_use_id": result.id,
+                        "content": result.output,
+                        "is_error": result.is_error
+                    })
+                })
+                .collect();
+            messages.push(json!({"role": "user", "content": results}));
+        }
+        // So does every tool round, while the model keeps calling tools.
+        if self.config.prompt_caching && !rounds.is_empty() {
+            if let Some(message) = messages.last_mut() {
+                cache_breakpoint(&mut message["content"]);
+            }
+        }
+        if !tools.is_empty() {
+            template["tools"] = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters
+                    })
+                })
+                .collect();
+        }
+        // Claude has no seed, and needs max_tokens, which is already set from the config.
+        template.as_object_mut().unwrap().extend(generation_fields(
+            generation,
+            ["temperature", "top_p", "max_tokens", "stop_sequences", ""],
+        ));
+        // Thinking counts toward max_tokens, so the budget is added to it. It can't be
+        // combined with a temperature or top_k, and only with a top_p of 0.95 or more.
+        if self.config.thinking_budget > 0 {
+            let budget = u64::from(self.config.thinking_budget);
+            if budget < 1024 {
+                return Err(ConverseError::Config(
+                    "thinking_budget needs to be at least 1024.".to_string(),
+                ));
+            }
+            let max_tokens = template["max_tokens"].as_u64().unwrap_or_default() + budget;
+            if max_tokens > MAX_OUTPUT_TOKENS {
+                return Err(ConverseError::Config(format!(
+                    "max_tokens and thinking_budget add up to {}, over Claude's limit of {}.",
+                    max_tokens, MAX_OUTPUT_TOKENS
+                )));
+            }
+            template["max_tokens"] = json!(max_tokens);
+            template["thinking"] = json!({"type": "enabled", "budget_tokens": budget});
+            let fields = template.as_object_mut().unwrap();
+            fields.remove("temperature");
+            fields.remove("top_k");
+            if let Some(top_p) = fields.get_mut("top_p") {
+                *top_p = json!(top_p.as_f64().unwrap_or(1.0).max(0.95));
+            }
+        }
+
+        Ok(template)
+    }
+
+    fn build_request(
+        &self,
+        client: &Client,
+        _model: &str,
+        data: &serde_json::Value,
+    ) -> RequestBuilder {
+        client
+            .post(URL)
+            .header("x-api-key", &self.config.api)
+            .header("anthropic-version", &self.config.anthropic_version)
+            .header("Content-Type", "application/json")
+            .json(data)
+    }
+
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+        let event: serde_json::Value = serde_json::from_str(data)?;
+        match event["type"].as_str() {
+            Some("message_start") => {
+                let usage = &event["message"]["usage"];
+                content.usage =
+                    Usage::from_json(usage, "input_tokens", "output_tokens").map(|counts| Usage {
+                        cache_creation_tokens: usage["cache_creation_input_tokens"]
+                            .as_u64()
+                            .unwrap_or_default(),
+                        cache_read_tokens: usage["cache_read_input_tokens"]
+                            .as_u64()
+                            .unwrap_or_default(),
+                        ..counts
+                    });
+                Ok(None)
+            }
+            // Has the final output token count and why the answer stopped.
+            Some("message_delta") => {
+                if let Some(output_tokens) = event["usage"]["output_tokens"].as_u64() {
+                    content
+                        .usage
+                        .get_or_insert_with(Usage::default)
+                        .output_tokens = output_tokens;
+                }
+                if let Some(reason) = event["delta"]["stop_reason"].as_str() {
+                    content.stop_reason = reason.to_string();
+                    content.truncated = reason == "max_tokens";
+                }
+                Ok(None)
+            }
+            Some("error") => Err(ConverseError::Provider(
+                error_message(data).unwrap_or_else(|| data.to_string()),
+            )),
+            Some("content_block_start") if event["content_block"]["type"] == "tool_use" => {
+                content.tool_calls.push(ToolCall {
+                    id: event["content_block"]["id"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    name: event["content_block"]["name"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    arguments: String::new(),
+                });
+                Ok(None)
+            }
+            Some("content_block_start") if event["content_block"]["type"] == "thinking" => {
+                if !content.thinking.is_empty() {
+                    content.thinking.push_str("\n\n");
+                }
+                content.thinking_blocks.push(ThinkingBlock::default());
+                Ok(None)
+            }
+            Some("content_block_start")
+                if event["content_block"]["type"] == "redacted_thinking" =>
+            {
+                content.thinking_blocks.push(ThinkingBlock {
+                    redacted: event["content_block"]["data"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    ..Default::default()
+                });
+                Ok(None)
+            }
+            Some("content_block_delta") if event["delta"]["type"] == "thinking_delta" => {
+                let thinking = event["delta"]["thinking"].as_str().unwrap_or_default();
+                content.thinking.push_str(thinking);
+                if let Some(block) = content.thinking_blocks.last_mut() {
+                    block.text.push_str(thinking);
+                }
+                Ok(None)
+            }
+            Some("content_block_delta") if event["delta"]["type"] == "signature_delta" => {
+                if let Some(block) = content.thinking_blocks.last_mut() {
+                    block.signature = event["delta"]["signature"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string();
+                }
+                Ok(None)
+            }
+            // Blocks are streamed one after the other, so the input belongs to the last call.
+            Some("content_block_delta") if event["delta"]["type"] == "input_json_delta" => {
+                if let Some(call) = content.tool_calls.last_mut() {
+                    call.arguments
+                        .push_str(event["delta"]["partial_json"].as_str().unwrap_or_default());
+                }
+                Ok(None)
+            }
+            Some("content_block_delta") => Ok(event
+                .pointer("/delta/text")
+                .and_then(|val| val.as_str())
+                .map(str::to_string)),
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_error(&self, response: &str) -> Option<String> {
+        error_message(response)
+    }
+
+    fn model_list_request(&self, client: &Client) -> Option<RequestBuilder> {
+        Some(
+            client
+                .get(MODELS_URL)
+                .header("x-api-key", &self.config.api)
+                .header("anthropic-version", &self.config.anthropic_version),
+        )
+    }
+
+    fn parse_model_list(&self, response: &str) -> Vec<String> {
+        let response_content: serde_json::Value =
+            serde_json::from_str(response).unwrap_or_default();
+        response_content["data"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|model| model["id"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Claude;
+    use crate::{
+        error::ConverseError,
+        models::Provider,
+        parser::config::{ConfigClaude, ConfigGeneration},
+    };
+
+    fn query(
+        thinking_budget: u32,
+        generation: ConfigGeneration,
+    ) -> Result<serde_json::Value, ConverseError> {
+        let claude = Claude::new(&ConfigClaude {
+            max_tokens: 1024,
+            thinking_budget,
+            ..Default::default()
+        });
+        claude.create_query(
+            "claude-sonnet-4-5",
+            &json!({"role": "user", "text": "Hi"}),
+            &json!({"chat": []}),
+            "",
+            &generation,
+            &[],
+        )
+    }
+
+    fn sampling() -> ConfigGeneration {
+        ConfigGeneration {
+            temperature: Some(0.7),
+            top_p: Some(0.5),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sends_sampling_without_thinking() {
+        let query = query(0, sampling()).unwrap();
+        assert_eq!(query["max_tokens"], 1024);
+        assert_eq!(query["temperature"], 0.7);
+        assert_eq!(query["top_p"], 0.5);
+        assert!(query.get("thinking").is_none());
+    }
+
+    #[test]
+    fn thinking_adds_its_budget_and_limits_sampling() {
+        let query = query(2048, sampling()).unwrap();
+        assert_eq!(query["max_tokens"], 3072);
+        assert_eq!(
+            query["thinking"],
+            json!({"type": "enabled", "budget_tokens": 2048})
+        );
+        assert!(query.get("temperature").is_none());
+        assert!(query.get("top_k").is_none());
+        assert_eq!(query["top_p"], 0.95);
+
+        let query = self::query(
+            2048,
+            ConfigGeneration {
+                top_p: Some(0.98),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(query["top_p"], 0.98);
+    }
+
+    #[test]
+    fn rejects_budgets_out_of_range() {
+        assert!(matches!(
+            query(512, ConfigGeneration::default()),
+            Err(ConverseError::Config(_))
+        ));
+        assert!(matches!(
+            query(63000, ConfigGeneration::default()),
+            Err(ConverseError::Config(_))
+        ));
+        let generation = ConfigGeneration {
+            max_output_tokens: Some(60000),
+            ..Default::default()
+        };
+        assert!(matches!(
+            query(4096, generation),
+            Err(ConverseError::Config(_))
+        ));
+        assert!(query(62976, ConfigGeneration::default()).is_ok());
     }
 }
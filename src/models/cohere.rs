@@ -1,93 +1,466 @@
-use reqwest::{Client, Error, StatusCode};
+use reqwest::{Client, RequestBuilder};
 use serde_json::json;
 
-use crate::parser::config::ConfigCohere;
+use crate::{
+    error::ConverseError,
+    parser::config::{ConfigCohere, ConfigGeneration, ConfigRetry},
+};
 
-use super::ChatContent;
+use super::{
+    chat_history, generation_fields, message_text, model_choices, sse_data, tool_rounds,
+    tools::{ToolCall, ToolSpec},
+    Capabilities, ChatContent, Provider, Source, Usage,
+};
 
-pub struct Cohere {}
+// Cohere describes parameters with python type names instead of a JSON schema.
+fn tool_definition(tool: &ToolSpec) -> serde_json::Value {
+    let required = tool.parameters["required"].as_array();
+    let parameters: serde_json::Map<String, serde_json::Value> = tool.parameters["properties"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, schema)| {
+            let kind = match schema["type"].as_str() {
+                Some("integer") => "int",
+                Some("number") => "float",
+                Some("boolean") => "bool",
+                Some("array") => "list",
+                Some("object") => "dict",
+                _ => "str",
+            };
+            let definition = json!({
+                "description": schema["description"],
+                "type": kind,
+                "required": required.is_some_and(|required| required.contains(&json!(name)))
+            });
+            (name.clone(), definition)
+        })
+        .collect();
+    json!({
+        "name": tool.name,
+        "description": tool.description,
+        "parameter_definitions": parameters
+    })
+}
+
+pub struct Cohere {
+    config: ConfigCohere,
+}
 
-const URL: &'static str = "https://api.cohere.ai/v1/chat";
+const URL: &str = "https://api.cohere.ai/v1/chat";
+const URL_V2: &str = "https://api.cohere.com/v2/chat";
+const MODELS_URL: &str = "https://api.cohere.ai/v1/models?endpoint=chat&page_size=1000";
 
 impl Cohere {
-    pub async fn request(
-        query: &str,
-        config: &ConfigCohere,
-        init_input: &serde_json::Value,
-    ) -> Result<ChatContent, Error> {
-        let url = format!("{}", URL);
-        let mut conversation =
-            Self::create_query(config.web_search, &config.conversation_input, init_input);
-        conversation.as_object_mut().unwrap().insert(
-            "message".to_string(),
-            serde_json::Value::String(query.to_string()),
-        );
-
-        let (response, status) = Self::send_request(&url, &conversation, &config.api).await?;
-        let result = Self::process_response(query, &response, status)?;
-
-        Ok(result)
-    }
-    async fn send_request(
-        url: &str,
-        data: &serde_json::Value,
-        api: &str,
-    ) -> Result<(String, StatusCode), Error> {
-        let response = Client::new()
-            .post(url)
-            .header("Authorization", format!("Bearer {}", api))
-            .header("Content-Type", "application/json")
-            .json(&data)
-            .send()
-            .await?;
-        let status = response.status();
-        let response = response.text().await?;
-        Ok((response, status))
-    }
-
-    fn process_response(
-        query: &str,
-        response: &str,
-        status: StatusCode,
-    ) -> Result<ChatContent, Error> {
-        let response_content: serde_json::Value = serde_json::from_str(response).unwrap();
-        let answer = response_content
-            .pointer("/text")
-            .and_then(|val| val.as_str())
-            .unwrap_or("");
-        let result = ChatContent {
-            question: query.to_string(),
-            answer: answer.to_string(),
-            status,
-        };
-        Ok(result)
+    pub fn new(config: &ConfigCohere) -> Self {
+        Self {
+            config: config.clone(),
+        }
     }
 
-    fn create_query(
-        web_search: bool,
-        conversation_input: &serde_json::Value,
+    fn is_v2(&self) -> bool {
+        self.config.api_version >= 2
+    }
+
+    fn create_query_v1(
+        &self,
+        model: &str,
+        query: &serde_json::Value,
         init_input: &serde_json::Value,
-    ) -> serde_json::Value {
-        let mut template = if web_search {
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut template = if self.config.web_search {
             json!({"chat_history": [], "connectors": [{"id": "web-search"}]})
         } else {
             json!({"chat_history": []})
         };
+        if !system_prompt.is_empty() {
+            template["preamble"] = json!(system_prompt);
+        }
+        let history = template["chat_history"].as_array_mut().unwrap();
+
+        // Images in the history are left out, since Cohere can't read them.
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            history.push(json!({ "role": role, "message": message_text(item)}))
+        }
+
+        if !model.is_empty() {
+            template["model"] = json!(model);
+        }
+        template["message"] = json!(message_text(query));
+        if !tools.is_empty() {
+            template["tools"] = tools.iter().map(tool_definition).collect();
+        }
+        // The results of every round are sent along with the message again.
+        let results: Vec<serde_json::Value> = tool_rounds(query)
+            .iter()
+            .flat_map(|round| round.calls.iter().zip(&round.results))
+            .map(|(call, result)| {
+                json!({
+                    "call": {"name": call.name, "parameters": call.arguments()},
+                    "outputs": [{"output": result.output}]
+                })
+            })
+            .collect();
+        if !results.is_empty() {
+            template["tool_results"] = json!(results);
+        }
+        template["stream"] = json!(true);
+        template.as_object_mut().unwrap().extend(generation_fields(
+            generation,
+            ["temperature", "p", "max_tokens", "stop_sequences", "seed"],
+        ));
+
+        Ok(template)
+    }
+
+    // Files attached to the query are sent as documents, so the answer can cite them.
+    fn create_query_v2(
+        &self,
+        model: &str,
+        query: &serde_json::Value,
+        init_input: &serde_json::Value,
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut messages = Vec::new();
+        if !system_prompt.is_empty() {
+            messages.push(json!({"role": "system", "content": system_prompt}));
+        }
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            let role = role.replace("model", "assistant");
+            messages.push(json!({"role": role, "content": message_text(item)}));
+        }
+        messages.push(json!({"role": "user", "content": query["text"]}));
+        for round in tool_rounds(query) {
+            let calls: Vec<serde_json::Value> = round
+                .calls
+                .iter()
+                .map(|call| {
+                    json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": {"name": call.name, "arguments": call.arguments().to_string()}
+                    })
+                })
+                .collect();
+            messages
+                .push(json!({"role": "assistant", "tool_plan": round.text, "tool_calls": calls}));
+            for result in &round.results {
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": result.id,
+                    "content": result.output
+                }));
+            }
+        }
+
+        let mut template = json!({"model": model, "messages": messages, "stream": true});
+        let documents: Vec<serde_json::Value> = query["context"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|file| json!({"id": file["path"], "data": {"title": file["path"], "text": file["text"]}}))
+            .collect();
+        if !documents.is_empty() {
+            template["documents"] = json!(documents);
+        }
+        if !tools.is_empty() {
+            template["tools"] = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters
+                        }
+                    })
+                })
+                .collect();
+        }
+        template.as_object_mut().unwrap().extend(generation_fields(
+            generation,
+            ["temperature", "p", "max_tokens", "stop_sequences", "seed"],
+        ));
+
+        Ok(template)
+    }
+
+    // v1 streams newline delimited json instead of server-sent events.
+    fn process_stream_v1(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        let event: serde_json::Value = serde_json::from_str(line)?;
+        match event["event_type"].as_str() {
+            Some("text-generation") => Ok(event["text"].as_str().map(str::to_string)),
+            Some("tool-calls-generation") => {
+                let calls = event["tool_calls"].as_array().into_iter().flatten();
+                for (index, call) in calls.enumerate() {
+                    let name = call["name"].as_str().unwrap_or_default().to_string();
+                    content.tool_calls.push(ToolCall {
+                        id: format!("{}-{}", name, index),
+                        name,
+                        arguments: call["parameters"].to_string(),
+                    });
+                }
+                Ok(None)
+            }
+            // Web search results, sent before the citations that refer to them by id.
+            Some("search-results") => {
+                let documents = event["documents"].as_array().into_iter().flatten();
+                content.documents.extend(documents.map(source));
+                Ok(None)
+            }
+            Some("citation-generation") => {
+                for citation in event["citations"].as_array().into_iter().flatten() {
+                    let end = citation["end"].as_u64().unwrap_or_default() as usize;
+                    for id in citation["document_ids"].as_array().into_iter().flatten() {
+                        let id = id.as_str().unwrap_or_default();
+                        let source = content
+                            .documents
+                            .iter()
+                            .find(|document| document.id == id)
+                            .cloned()
+                            .unwrap_or_else(|| Source {
+                                id: id.to_string(),
+                                title: id.to_string(),
+                                ..Default::default()
+                            });
+                        content.cite(end, source);
+                    }
+                }
+                Ok(None)
+            }
+            Some("stream-end") if event["finish_reason"] == "ERROR" => {
+                Err(ConverseError::Provider(
+                    event["response"]["text"]
+                        .as_str()
+                        .unwrap_or("Cohere could not finish the answer.")
+                        .to_string(),
+                ))
+            }
+            Some("stream-end") => {
+                let usage = &event["response"]["meta"]["billed_units"];
+                content.usage = Usage::from_json(usage, "input_tokens", "output_tokens");
+                content.stop_reason = event["finish_reason"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                content.truncated = content.stop_reason == "MAX_TOKENS";
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn process_stream_v2(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+        let event: serde_json::Value = serde_json::from_str(data)?;
+        let message = &event["delta"]["message"];
+        match event["type"].as_str() {
+            Some("content-delta") => Ok(message["content"]["text"].as_str().map(str::to_string)),
+            // The plan is shown like the text other providers send before their tool calls.
+            Some("tool-plan-delta") => Ok(message["tool_plan"].as_str().map(str::to_string)),
+            Some("tool-call-start") => {
+                let call = &message["tool_calls"];
+                content.tool_calls.push(ToolCall {
+                    id: call["id"].as_str().unwrap_or_default().to_string(),
+                    name: call["function"]["name"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    arguments: call["function"]["arguments"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                });
+                Ok(None)
+            }
+            Some("tool-call-delta") => {
+                if let (Some(call), Some(arguments)) = (
+                    content.tool_calls.last_mut(),
+                    message["tool_calls"]["function"]["arguments"].as_str(),
+                ) {
+                    call.arguments.push_str(arguments);
+                }
+                Ok(None)
+            }
+            Some("citation-start") => {
+                let citation = &message["citations"];
+                let end = citation["end"].as_u64().unwrap_or_default() as usize;
+                for cited in citation["sources"].as_array().into_iter().flatten() {
+                    // Documents and tool results both describe themselves.
+                    let fields = if cited["type"] == "tool" {
+                        &cited["tool_output"]
+                    } else {
+                        &cited["document"]
+                    };
+                    let mut source = source(fields);
+                    source.id = cited["id"].as_str().unwrap_or_default().to_string();
+                    if source.title.is_empty() && source.url.is_empty() {
+                        // Tool results are cited as "<call id>:<index>".
+                        let call_id = source.id.split(':').next().unwrap_or_default();
+                        source.title = content
+                            .tool_rounds
+                            .iter()
+                            .flat_map(|round| &round.calls)
+                            .find(|call| call.id == call_id)
+                            .map(|call| format!("Result of {}", call.name))
+                            .unwrap_or_else(|| source.id.clone());
+                    }
+                    content.cite(end, source);
+                }
+                Ok(None)
+            }
+            Some("message-end") if event["delta"]["finish_reason"] == "ERROR" => Err(
+                ConverseError::Provider("Cohere could not finish the answer.".to_string()),
+            ),
+            Some("message-end") => {
+                let usage = &event["delta"]["usage"]["billed_units"];
+                content.usage = Usage::from_json(usage, "input_tokens", "output_tokens");
+                content.stop_reason = event["delta"]["finish_reason"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                content.truncated = content.stop_reason == "MAX_TOKENS";
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// A document as Cohere describes it, with a title and url when it has them.
+fn source(document: &serde_json::Value) -> Source {
+    let field = |name: &str| document[name].as_str().unwrap_or_default().to_string();
+    Source {
+        id: field("id"),
+        title: field("title"),
+        url: field("url"),
+    }
+}
+
+impl Provider for Cohere {
+    fn name(&self) -> &str {
+        "Cohere"
+    }
+
+    fn priority(&self) -> u32 {
+        self.config.use_model
+    }
+
+    fn retry(&self) -> &ConfigRetry {
+        &self.config.retry
+    }
 
-        for item in conversation_input.as_array().unwrap() {
-            template["chat_history"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({ "role": item["role"], "message": item["text"]}))
+    fn generation(&self) -> &ConfigGeneration {
+        &self.config.generation
+    }
+
+    fn system_prompt(&self) -> &str {
+        &self.config.system_prompt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            web_search: self.config.web_search && !self.is_v2(),
+            max_tokens: true,
+            images: false,
+            tools: true,
         }
+    }
+
+    // Without a model, v1 uses Cohere's default one.
+    fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn requires_model(&self) -> bool {
+        self.is_v2()
+    }
 
-        for item in init_input.as_array().unwrap() {
-            template["chat_history"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({ "role": item["role"], "message": item["text"]}))
+    fn models(&self) -> Vec<String> {
+        model_choices(&self.config.model, &self.config.models)
+    }
+
+    fn create_query(
+        &self,
+        model: &str,
+        query: &serde_json::Value,
+        init_input: &serde_json::Value,
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        if self.is_v2() {
+            self.create_query_v2(model, query, init_input, system_prompt, generation, tools)
+        } else {
+            self.create_query_v1(model, query, init_input, system_prompt, generation, tools)
+        }
+    }
+
+    fn build_request(
+        &self,
+        client: &Client,
+        _model: &str,
+        data: &serde_json::Value,
+    ) -> RequestBuilder {
+        client
+            .post(if self.is_v2() { URL_V2 } else { URL })
+            .header("Authorization", format!("Bearer {}", self.config.api))
+            .header("Content-Type", "application/json")
+            .json(data)
+    }
+
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        if self.is_v2() {
+            self.process_stream_v2(line, content)
+        } else {
+            self.process_stream_v1(line, content)
         }
+    }
+
+    fn parse_error(&self, response: &str) -> Option<String> {
+        let response_content: serde_json::Value = serde_json::from_str(response).ok()?;
+        response_content["message"].as_str().map(str::to_string)
+    }
+
+    fn model_list_request(&self, client: &Client) -> Option<RequestBuilder> {
+        Some(
+            client
+                .get(MODELS_URL)
+                .header("Authorization", format!("Bearer {}", self.config.api)),
+        )
+    }
 
-        template
+    fn parse_model_list(&self, response: &str) -> Vec<String> {
+        let response_content: serde_json::Value =
+            serde_json::from_str(response).unwrap_or_default();
+        response_content["models"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|model| model["name"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 }
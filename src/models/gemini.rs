@@ -1,90 +1,485 @@
-use reqwest::{Client, Error, StatusCode};
+use reqwest::{Client, RequestBuilder};
 use serde_json::json;
 
-use crate::parser::config::ConfigGemini;
+use crate::{
+    error::ConverseError,
+    parser::config::{ConfigGemini, ConfigGeneration, ConfigRetry},
+};
 
-use super::ChatContent;
+use super::{
+    chat_history, generation_fields, message_images, message_text, model_choices, sse_data,
+    tool_rounds,
+    tools::{ToolCall, ToolSpec},
+    Capabilities, ChatContent, Provider, Source, Usage,
+};
 
-pub struct Gemini {}
+fn parts(item: &serde_json::Value) -> serde_json::Value {
+    let mut parts = vec![json!({"text": message_text(item)})];
+    parts.extend(
+        message_images(item).iter().map(
+            |image| json!({"inline_data": {"mime_type": image.mime_type, "data": image.data}}),
+        ),
+    );
+    json!(parts)
+}
+
+// The harm categories Gemini flagged, e.g. "harassment, dangerous content".
+fn flagged_categories(ratings: &serde_json::Value) -> String {
+    let categories: Vec<String> = ratings
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|rating| {
+            rating["blocked"] == true || matches!(rating["probability"].as_str(), Some("HIGH"))
+        })
+        .filter_map(|rating| rating["category"].as_str())
+        .map(|category| {
+            category
+                .trim_start_matches("HARM_CATEGORY_")
+                .replace('_', " ")
+                .to_lowercase()
+        })
+        .collect();
+    categories.join(", ")
+}
+
+// Explains why Gemini refused, given its reason and the categories behind it.
+fn block_notice(subject: &str, reason: &str, categories: &str) -> String {
+    let notice = match reason {
+        "SAFETY" => format!("Gemini blocked {} for safety reasons", subject),
+        "RECITATION" => format!(
+            "Gemini stopped {} because it was reciting copyrighted material",
+            subject
+        ),
+        "BLOCKLIST" => format!(
+            "Gemini blocked {} because it contains blocked terms",
+            subject
+        ),
+        "PROHIBITED_CONTENT" => format!(
+            "Gemini blocked {} because it may contain prohibited content",
+            subject
+        ),
+        "SPII" => format!(
+            "Gemini blocked {} because it may contain personal information",
+            subject
+        ),
+        reason => format!("Gemini blocked {} ({})", subject, reason),
+    };
+    if categories.is_empty() {
+        format!("{}.", notice)
+    } else {
+        format!("{}: {}.", notice, categories)
+    }
+}
+
+// Cites the pages Google Search found for the answer. Supports give where the text
+// they back ends in bytes, which are counted in characters for the footnote marks.
+fn cite_grounding(metadata: &serde_json::Value, answer: &str, content: &mut ChatContent) {
+    for query in metadata["webSearchQueries"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|query| query.as_str())
+    {
+        if !content.searches.iter().any(|search| search == query) {
+            content.searches.push(query.to_string());
+        }
+    }
+    let pages: Vec<Source> = metadata["groundingChunks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|chunk| Source {
+            id: String::new(),
+            title: chunk["web"]["title"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            url: chunk["web"]["uri"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect();
+    for support in metadata["groundingSupports"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let mut end = support["segment"]["endIndex"].as_u64().unwrap_or_default() as usize;
+        end = end.min(answer.len());
+        while !answer.is_char_boundary(end) {
+            end -= 1;
+        }
+        let end = answer[..end].chars().count();
+        for index in support["groundingChunkIndices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|index| index.as_u64())
+        {
+            if let Some(page) = pages.get(index as usize) {
+                content.cite(end, page.clone());
+            }
+        }
+    }
+}
+
+pub struct Gemini {
+    config: ConfigGemini,
+}
 
 const URL: &str =
-    "https://generativelanguage.googleapis.com/v1beta/models/MODEL:generateContent?key=";
+    "https://generativelanguage.googleapis.com/v1beta/models/MODEL:streamGenerateContent?alt=sse&key=";
+const MODELS_URL: &str =
+    "https://generativelanguage.googleapis.com/v1beta/models?pageSize=1000&key=";
 
 impl Gemini {
-    pub async fn request(
-        query: &str,
-        config: &ConfigGemini,
+    pub fn new(config: &ConfigGemini) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl Provider for Gemini {
+    fn name(&self) -> &str {
+        "Gemini"
+    }
+
+    fn priority(&self) -> u32 {
+        self.config.use_model
+    }
+
+    fn retry(&self) -> &ConfigRetry {
+        &self.config.retry
+    }
+
+    fn generation(&self) -> &ConfigGeneration {
+        &self.config.generation
+    }
+
+    fn system_prompt(&self) -> &str {
+        &self.config.system_prompt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            web_search: self.config.grounding,
+            max_tokens: true,
+            images: true,
+            tools: !self.config.grounding,
+        }
+    }
+
+    fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn models(&self) -> Vec<String> {
+        model_choices(&self.config.model, &self.config.models)
+    }
+
+    fn create_query(
+        &self,
+        _model: &str,
+        query: &serde_json::Value,
         init_input: &serde_json::Value,
-    ) -> Result<ChatContent, Error> {
-        let url = format!("{}{}", URL.replace("MODEL", &config.model), config.api);
-        let mut conversation = Self::create_query(&config.conversation_input, &init_input);
-        conversation["contents"].as_array_mut().unwrap().push(json!(
-            {
-            "role": "user",
-            "parts": [{
-                "text": query
-            }]
-        }));
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut template = json!({"contents": []});
+        if !system_prompt.is_empty() {
+            template["systemInstruction"] = json!({"parts": [{"text": system_prompt}]});
+        }
+        let contents = template["contents"].as_array_mut().unwrap();
+
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            contents.push(json!({"role": role, "parts": parts(item)}));
+        }
 
-        let (response, status) = Self::send_request(&url, &conversation).await?;
-        let result = Self::process_response(query, &response, status)?;
+        contents.push(json!({"role": "user", "parts": parts(query)}));
+        for round in tool_rounds(query) {
+            let mut model_parts = Vec::new();
+            if !round.text.is_empty() {
+                model_parts.push(json!({"text": round.text}));
+            }
+            model_parts.extend(round.calls.iter().map(
+                |call| json!({"functionCall": {"name": call.name, "args": call.arguments()}}),
+            ));
+            contents.push(json!({"role": "model", "parts": model_parts}));
+            let responses: Vec<serde_json::Value> = round
+                .results
+                .iter()
+                .map(|result| {
+                    let key = if result.is_error { "error" } else { "output" };
+                    json!({"functionResponse": {"name": result.name, "response": {key: result.output}}})
+                })
+                .collect();
+            contents.push(json!({"role": "user", "parts": responses}));
+        }
+        if !tools.is_empty() {
+            // Functions without parameters are rejected when given an empty schema.
+            let declarations: Vec<serde_json::Value> = tools
+                .iter()
+                .map(|tool| {
+                    let mut declaration =
+                        json!({"name": tool.name, "description": tool.description});
+                    if tool.parameters["properties"]
+                        .as_object()
+                        .is_some_and(|properties| !properties.is_empty())
+                    {
+                        declaration["parameters"] = tool.parameters.clone();
+                    }
+                    declaration
+                })
+                .collect();
+            template["tools"] = json!([{ "functionDeclarations": declarations }]);
+        }
+        if self.config.grounding {
+            template["tools"] = json!([{ "google_search": {} }]);
+        }
+        let generation_config = generation_fields(
+            generation,
+            [
+                "temperature",
+                "topP",
+                "maxOutputTokens",
+                "stopSequences",
+                "seed",
+            ],
+        );
+        if !generation_config.is_empty() {
+            template["generationConfig"] = generation_config.into();
+        }
+        if !self.config.safety_settings.is_empty() {
+            let mut settings: Vec<(&String, &String)> =
+                self.config.safety_settings.iter().collect();
+            settings.sort();
+            template["safetySettings"] = settings
+                .into_iter()
+                .map(|(category, threshold)| json!({"category": category, "threshold": threshold}))
+                .collect();
+        }
 
-        Ok(result)
+        Ok(template)
     }
 
-    async fn send_request(
-        url: &str,
+    fn build_request(
+        &self,
+        client: &Client,
+        model: &str,
         data: &serde_json::Value,
-    ) -> Result<(String, StatusCode), Error> {
-        let response = Client::new()
+    ) -> RequestBuilder {
+        let url = format!("{}{}", URL.replace("MODEL", model), self.config.api);
+        client
             .post(url)
             .header("Content-Type", "application/json")
             .json(data)
-            .send()
-            .await?;
-        let status = response.status();
-        let response = response.text().await?;
-        Ok((response, status))
-    }
-
-    fn process_response(
-        query: &str,
-        response: &str,
-        status: StatusCode,
-    ) -> Result<ChatContent, Error> {
-        let response_content: serde_json::Value = serde_json::from_str(response).unwrap();
-        let answer = response_content
-            .pointer("/candidates/0/content/parts/0/text")
-            .and_then(|val| val.as_str())
-            .unwrap_or("");
-        let result = ChatContent {
-            question: query.to_string(),
-            answer: answer.to_string(),
-            status,
-        };
-        Ok(result)
     }
 
-    fn create_query(
-        conversation_input: &serde_json::Value,
-        init_input: &serde_json::Value,
-    ) -> serde_json::Value {
-        let mut template = json!({"contents": []});
-
-        for item in conversation_input.as_array().unwrap() {
-            template["contents"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({"role": item["role"], "parts": [{"text": item["text"]}]}));
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+        let chunk: serde_json::Value = serde_json::from_str(data)?;
+        if let Some(message) = self.parse_error(data) {
+            return Err(ConverseError::Provider(message));
+        }
+        // Every chunk has the totals so far.
+        let usage = &chunk["usageMetadata"];
+        if let Some(usage) = Usage::from_json(usage, "promptTokenCount", "candidatesTokenCount") {
+            content.usage = Some(usage);
+        }
+        // A blocked prompt gets no candidates at all.
+        if let Some(reason) = chunk["promptFeedback"]["blockReason"].as_str() {
+            let categories = flagged_categories(&chunk["promptFeedback"]["safetyRatings"]);
+            content.notice = block_notice("the prompt", reason, &categories);
+            content.blocked = true;
+        }
+        let candidate = &chunk["candidates"][0];
+        if let Some(reason) = candidate["finishReason"].as_str() {
+            content.stop_reason = reason.to_string();
         }
+        match candidate["finishReason"].as_str() {
+            None | Some("STOP") | Some("FINISH_REASON_UNSPECIFIED") => {}
+            Some("MAX_TOKENS") => content.truncated = true,
+            Some("MALFORMED_FUNCTION_CALL") => {
+                content.notice = "Gemini stopped after calling a tool incorrectly.".to_string()
+            }
+            Some("OTHER") => content.notice = "Gemini stopped the answer early.".to_string(),
+            Some(reason) => {
+                let categories = flagged_categories(&candidate["safetyRatings"]);
+                content.notice = block_notice("the answer", reason, &categories);
+                content.blocked = true;
+            }
+        }
+        let parts = chunk.pointer("/candidates/0/content/parts");
+        // Gemini has no call ids, and sends each call whole.
+        for call in parts
+            .and_then(|parts| parts.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|part| part.get("functionCall"))
+        {
+            let name = call["name"].as_str().unwrap_or_default().to_string();
+            content.tool_calls.push(ToolCall {
+                id: name.clone(),
+                name,
+                arguments: call["args"].to_string(),
+            });
+        }
+        let text: Option<String> = chunk
+            .pointer("/candidates/0/content/parts")
+            .and_then(|parts| parts.as_array())
+            .map(|parts| {
+                parts
+                    .iter()
+                    .filter_map(|part| part["text"].as_str())
+                    .collect()
+            });
+        // Tools are off while grounding, so the whole answer is from this response.
+        let metadata = &candidate["groundingMetadata"];
+        if metadata.is_object() {
+            let answer = format!("{}{}", content.answer, text.as_deref().unwrap_or_default());
+            cite_grounding(metadata, &answer, content);
+        }
+        Ok(text)
+    }
 
-        for item in init_input.as_array().unwrap() {
-            template["contents"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({"parts": [{"text": item["text"]}], "role": item["role"]}));
+    // Errors from the streaming endpoint may be wrapped in an array.
+    fn parse_error(&self, response: &str) -> Option<String> {
+        let response_content: serde_json::Value = serde_json::from_str(response).ok()?;
+        let error = match &response_content {
+            serde_json::Value::Array(items) => &items.first()?["error"],
+            response_content => &response_content["error"],
+        };
+        let message = error["message"].as_str()?;
+        match error["status"].as_str() {
+            Some(status) => Some(format!("{}: {}", status, message)),
+            None => Some(message.to_string()),
         }
+    }
+
+    fn model_list_request(&self, client: &Client) -> Option<RequestBuilder> {
+        Some(client.get(format!("{}{}", MODELS_URL, self.config.api)))
+    }
+
+    // Embedding and other models that can't chat are left out.
+    fn parse_model_list(&self, response: &str) -> Vec<String> {
+        let response_content: serde_json::Value =
+            serde_json::from_str(response).unwrap_or_default();
+        response_content["models"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter(|model| {
+                        model["supportedGenerationMethods"]
+                            .as_array()
+                            .is_some_and(|methods| {
+                                methods.iter().any(|method| method == "generateContent")
+                            })
+                    })
+                    .filter_map(|model| model["name"].as_str())
+                    .map(|name| name.trim_start_matches("models/").to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{cite_grounding, Gemini};
+    use crate::{
+        models::{mark_citations, ChatContent, Provider, ToolSpec},
+        parser::config::{ConfigGemini, ConfigGeneration},
+    };
+
+    fn metadata(supports: &[(usize, &[u64])]) -> serde_json::Value {
+        json!({
+            "webSearchQueries": ["wetter köln", "wetter köln"],
+            "groundingChunks": [
+                {"web": {"title": "wetter.de", "uri": "https://example.com/a"}},
+                {"web": {"title": "東京", "uri": "https://example.com/b"}}
+            ],
+            "groundingSupports": supports
+                .iter()
+                .map(|(end, chunks)| {
+                    json!({"segment": {"endIndex": end}, "groundingChunkIndices": chunks})
+                })
+                .collect::<Vec<_>>()
+        })
+    }
+
+    fn cite(answer: &str, supports: &[(usize, &[u64])]) -> (ChatContent, String) {
+        let mut content = ChatContent::default();
+        cite_grounding(&metadata(supports), answer, &mut content);
+        let marked = mark_citations(answer, &content.citations, 0);
+        (content, marked)
+    }
+
+    #[test]
+    fn marks_byte_offsets_on_character_boundaries() {
+        let answer = "In Köln regnet es. 東京は晴れです。";
+        let first = "In Köln regnet es.".len();
+        let (content, marked) = cite(answer, &[(first, &[0]), (answer.len(), &[0, 1])]);
+        assert_eq!(marked, "In Köln regnet es.[1] 東京は晴れです。[1][2]");
+        assert_eq!(content.searches, ["wetter köln"]);
+        assert_eq!(content.sources.len(), 2);
+        assert_eq!(content.sources[1].title, "東京");
+    }
+
+    #[test]
+    fn rounds_offsets_inside_a_character_down() {
+        let answer = "東京は晴れ";
+        // One byte into 京, which starts at byte 3.
+        let (_, marked) = cite(answer, &[(4, &[1])]);
+        assert_eq!(marked, "東[1]京は晴れ");
+    }
+
+    #[test]
+    fn skips_unknown_pages_and_clamps_offsets() {
+        let answer = "Grüße";
+        let (content, marked) = cite(answer, &[(100, &[0, 7])]);
+        assert_eq!(marked, "Grüße[1]");
+        assert_eq!(content.sources.len(), 1);
+    }
+
+    #[test]
+    fn grounding_replaces_tools_with_google_search() {
+        let gemini = Gemini::new(&ConfigGemini {
+            grounding: true,
+            ..Default::default()
+        });
+        let capabilities = gemini.capabilities();
+        assert!(capabilities.web_search);
+        assert!(!capabilities.tools);
+
+        let tools = [ToolSpec {
+            name: "calculator".to_string(),
+            description: String::new(),
+            parameters: json!({"type": "object", "properties": {}}),
+        }];
+        let query = gemini
+            .create_query(
+                "gemini-2.5-pro",
+                &json!({"role": "user", "text": "Hi"}),
+                &json!({"chat": []}),
+                "",
+                &ConfigGeneration::default(),
+                &tools,
+            )
+            .unwrap();
+        assert_eq!(query["tools"], json!([{ "google_search": {} }]));
 
-        template
+        let gemini = Gemini::new(&ConfigGemini::default());
+        assert!(gemini.capabilities().tools);
+        assert!(!gemini.capabilities().web_search);
     }
 }
@@ -0,0 +1,217 @@
+use reqwest::{Client, RequestBuilder};
+use serde_json::json;
+
+use crate::{
+    error::ConverseError,
+    parser::config::{ConfigGeneration, ConfigOllama, ConfigRetry},
+};
+
+use super::{
+    chat_history, error_message, generation_fields, message_images, message_text, model_choices,
+    tools::ToolSpec, Capabilities, ChatContent, Provider, Usage,
+};
+
+// Ollama takes the images of a message as a list of base64 strings.
+fn message(role: &str, item: &serde_json::Value) -> serde_json::Value {
+    let mut message = json!({"role": role, "content": message_text(item)});
+    let images = message_images(item);
+    if !images.is_empty() {
+        message["images"] = images.iter().map(|image| image.data.clone()).collect();
+    }
+    message
+}
+
+pub struct Ollama {
+    config: ConfigOllama,
+}
+
+impl Ollama {
+    pub fn new(config: &ConfigOllama) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.host.trim_end_matches('/'), path)
+    }
+}
+
+impl Provider for Ollama {
+    fn name(&self) -> &str {
+        "Ollama"
+    }
+
+    fn priority(&self) -> u32 {
+        self.config.use_model
+    }
+
+    fn retry(&self) -> &ConfigRetry {
+        &self.config.retry
+    }
+
+    fn generation(&self) -> &ConfigGeneration {
+        &self.config.generation
+    }
+
+    fn system_prompt(&self) -> &str {
+        &self.config.system_prompt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_tokens: true,
+            images: true,
+            ..Default::default()
+        }
+    }
+
+    fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn models(&self) -> Vec<String> {
+        model_choices(&self.config.model, &self.config.models)
+    }
+
+    fn create_query(
+        &self,
+        model: &str,
+        query: &serde_json::Value,
+        init_input: &serde_json::Value,
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        _tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut template = json!({"model": model, "stream": true, "messages": []});
+        let messages = template["messages"].as_array_mut().unwrap();
+        if !system_prompt.is_empty() {
+            messages.push(json!({"role": "system", "content": system_prompt}));
+        }
+
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            let role = role.replace("model", "assistant");
+            messages.push(message(&role, item));
+        }
+
+        messages.push(message("user", query));
+        let options = generation_fields(
+            generation,
+            ["temperature", "top_p", "num_predict", "stop", "seed"],
+        );
+        if !options.is_empty() {
+            template["options"] = options.into();
+        }
+
+        Ok(template)
+    }
+
+    fn build_request(
+        &self,
+        client: &Client,
+        _model: &str,
+        data: &serde_json::Value,
+    ) -> RequestBuilder {
+        client
+            .post(self.url("/api/chat"))
+            .header("Content-Type", "application/json")
+            .json(data)
+    }
+
+    // Ollama streams newline delimited json instead of server-sent events.
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        if let Some(message) = error_message(line) {
+            return Err(ConverseError::Provider(message));
+        }
+        let chunk: serde_json::Value = serde_json::from_str(line)?;
+        if chunk["done"] == true {
+            content.usage = Usage::from_json(&chunk, "prompt_eval_count", "eval_count");
+            content.stop_reason = chunk["done_reason"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            content.truncated = content.stop_reason == "length";
+        }
+        Ok(chunk
+            .pointer("/message/content")
+            .and_then(|val| val.as_str())
+            .map(str::to_string))
+    }
+
+    fn parse_error(&self, response: &str) -> Option<String> {
+        error_message(response)
+    }
+
+    fn model_list_request(&self, client: &Client) -> Option<RequestBuilder> {
+        Some(client.get(self.url("/api/tags")))
+    }
+
+    fn parse_model_list(&self, response: &str) -> Vec<String> {
+        let response_content: serde_json::Value =
+            serde_json::from_str(response).unwrap_or_default();
+        response_content["models"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|model| model["name"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Ollama;
+    use crate::{
+        models::{request, test_server, Provider},
+        parser::config::ConfigOllama,
+    };
+
+    #[tokio::test]
+    async fn streams_from_a_local_server() {
+        let body = "{\"message\":{\"content\":\"Hel\"},\"done\":false}\n\
+            {\"message\":{\"content\":\"lo\"},\"done\":true,\"done_reason\":\"stop\",\"prompt_eval_count\":7,\"eval_count\":2}\n";
+        let (url, server) = test_server::serve("application/x-ndjson", body).await;
+        let provider = Ollama::new(&ConfigOllama {
+            host: format!("{}/", url),
+            ..Default::default()
+        });
+        let query = json!({"role": "user", "text": "Hi"});
+        let content = request(
+            &provider,
+            "llama3",
+            &query,
+            &json!({"chat": []}),
+            &[],
+            1,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(content.answer, "Hello");
+        let usage = content.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (7, 2));
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /api/chat "));
+        assert!(request.contains(r#""model":"llama3""#));
+    }
+
+    #[test]
+    fn lists_the_installed_models() {
+        let provider = Ollama::new(&ConfigOllama::default());
+        let tags = r#"{"models":[{"name":"llama3:latest"},{"name":"mistral:7b"}]}"#;
+        assert_eq!(
+            provider.parse_model_list(tags),
+            ["llama3:latest", "mistral:7b"]
+        );
+    }
+}
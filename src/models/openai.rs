@@ -1,89 +1,365 @@
-use reqwest::{Client, Error, StatusCode};
+use std::collections::HashMap;
+
+use reqwest::{Client, RequestBuilder};
 use serde_json::json;
 
-use crate::parser::config::ConfigOpenAI;
+use crate::{
+    error::ConverseError,
+    parser::config::{ConfigCustom, ConfigGeneration, ConfigOpenAI, ConfigRetry},
+};
+
+use super::{
+    chat_history, error_message, generation_fields, message_images, message_text, model_choices,
+    sse_data, tool_rounds,
+    tools::{ToolCall, ToolSpec},
+    Capabilities, ChatContent, Provider, Usage,
+};
 
-use super::ChatContent;
+// Also used for `[[custom]]` endpoints that speak the same protocol.
+pub struct OpenAI {
+    name: String,
+    base_url: String,
+    headers: HashMap<String, String>,
+    config: ConfigOpenAI,
+}
 
-pub struct OpenAI {}
+const URL: &str = "https://api.openai.com/v1";
 
-const URL: &str = "https://api.openai.com/v1/chat/completions";
+// Plain text, unless images are attached.
+fn content(item: &serde_json::Value) -> serde_json::Value {
+    let text = message_text(item);
+    let images = message_images(item);
+    if images.is_empty() {
+        return json!(text);
+    }
+    let mut parts = vec![json!({"type": "text", "text": text})];
+    parts.extend(
+        images
+            .iter()
+            .map(|image| json!({"type": "image_url", "image_url": {"url": image.data_uri()}})),
+    );
+    json!(parts)
+}
 
 impl OpenAI {
-    pub async fn request(
-        query: &str,
-        config: &ConfigOpenAI,
-        init_input: &serde_json::Value,
-    ) -> Result<ChatContent, Error> {
-        let mut conversation =
-            Self::create_query(&config.conversation_input, &config.model, init_input);
-        conversation["messages"]
-            .as_array_mut()
-            .unwrap()
-            .push(json!({ "role": "user", "content": query }));
+    pub fn new(config: &ConfigOpenAI) -> Self {
+        Self {
+            name: "OpenAI".to_string(),
+            base_url: URL.to_string(),
+            headers: HashMap::new(),
+            config: config.clone(),
+        }
+    }
 
-        let (response, status) = Self::send_request(URL, &config.api, &conversation).await?;
-        let result = Self::process_response(query, &response, status)?;
+    pub fn custom(config: &ConfigCustom) -> Self {
+        Self {
+            name: config.name.clone(),
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            headers: config.headers.clone(),
+            config: ConfigOpenAI {
+                api: config.api.clone(),
+                use_model: config.use_model,
+                system_prompt: config.system_prompt.clone(),
+                conversation_input: config.conversation_input.clone(),
+                retry: config.retry.clone(),
+                model: config.model.clone(),
+                models: config.models.clone(),
+                generation: config.generation.clone(),
+                reasoning_effort: config.reasoning_effort.clone(),
+            },
+        }
+    }
 
-        Ok(result)
+    fn with_headers(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if !self.config.api.is_empty() {
+            request = request.header("Authorization", format!("Bearer {}", self.config.api));
+        }
+        for (key, value) in &self.headers {
+            request = request.header(key, value);
+        }
+        request
     }
+}
 
-    async fn send_request(
-        url: &str,
-        api: &str,
-        data: &serde_json::Value,
-    ) -> Result<(String, StatusCode), Error> {
-        let response = Client::new()
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", api))
-            .json(data)
-            .send()
-            .await?;
-        let status = response.status();
-        let response = response.text().await?;
-        Ok((response, status))
-    }
-
-    fn process_response(
-        query: &str,
-        response: &str,
-        status: StatusCode,
-    ) -> Result<ChatContent, Error> {
-        let response_content: serde_json::Value = serde_json::from_str(response).unwrap();
-        let answer = response_content
-            .pointer("/choices/0/message/content")
-            .and_then(|val| val.as_str())
-            .unwrap_or("");
-        let result = ChatContent {
-            question: query.to_string(),
-            answer: answer.to_string(),
-            status,
-        };
-        Ok(result)
+impl Provider for OpenAI {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> u32 {
+        self.config.use_model
+    }
+
+    fn retry(&self) -> &ConfigRetry {
+        &self.config.retry
+    }
+
+    fn generation(&self) -> &ConfigGeneration {
+        &self.config.generation
+    }
+
+    fn system_prompt(&self) -> &str {
+        &self.config.system_prompt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_tokens: true,
+            images: true,
+            tools: true,
+            ..Default::default()
+        }
+    }
+
+    fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn models(&self) -> Vec<String> {
+        model_choices(&self.config.model, &self.config.models)
     }
 
     fn create_query(
-        conversation_input: &serde_json::Value,
+        &self,
         model: &str,
+        query: &serde_json::Value,
         init_input: &serde_json::Value,
-    ) -> serde_json::Value {
-        let mut template = json!({"model": model, "messages": []});
-
-        for item in conversation_input.as_array().unwrap() {
-            template["messages"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({"role": item["role"].as_str().unwrap().replace("model", "assistant"), "content": item["text"] }));
+        system_prompt: &str,
+        generation: &ConfigGeneration,
+        tools: &[ToolSpec],
+    ) -> Result<serde_json::Value, ConverseError> {
+        let mut template = json!({
+            "model": model,
+            "stream": true,
+            "stream_options": {"include_usage": true},
+            "messages": []
+        });
+        let messages = template["messages"].as_array_mut().unwrap();
+        if !system_prompt.is_empty() {
+            messages.push(json!({"role": "system", "content": system_prompt}));
+        }
+
+        for (role, item) in chat_history(&self.config.conversation_input, init_input)? {
+            let role = role.replace("model", "assistant");
+            messages.push(json!({"role": role, "content": content(item)}));
         }
 
-        for item in init_input.as_array().unwrap() {
-            template["messages"]
-                .as_array_mut()
-                .unwrap()
-                .push(json!({"role": item["role"].as_str().unwrap().replace("model", "assistant"), "content": item["text"] }));
+        messages.push(json!({ "role": "user", "content": content(query) }));
+        for round in tool_rounds(query) {
+            let calls: Vec<serde_json::Value> = round
+                .calls
+                .iter()
+                .map(|call| {
+                    json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": {"name": call.name, "arguments": call.arguments().to_string()}
+                    })
+                })
+                .collect();
+            messages.push(json!({"role": "assistant", "content": round.text, "tool_calls": calls}));
+            for result in round.results {
+                messages.push(
+                    json!({"role": "tool", "tool_call_id": result.id, "content": result.output}),
+                );
+            }
         }
+        if !tools.is_empty() {
+            template["tools"] = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters
+                        }
+                    })
+                })
+                .collect();
+        }
+        template.as_object_mut().unwrap().extend(generation_fields(
+            generation,
+            ["temperature", "top_p", "max_tokens", "stop", "seed"],
+        ));
+        // Reasoning models take max_completion_tokens instead of max_tokens.
+        if !self.config.reasoning_effort.is_empty() {
+            template["reasoning_effort"] = json!(self.config.reasoning_effort);
+            if let Some(max_tokens) = template.as_object_mut().unwrap().remove("max_tokens") {
+                template["max_completion_tokens"] = max_tokens;
+            }
+        }
+
+        Ok(template)
+    }
+
+    fn build_request(
+        &self,
+        client: &Client,
+        _model: &str,
+        data: &serde_json::Value,
+    ) -> RequestBuilder {
+        let request = client
+            .post(format!("{}/chat/completions", self.base_url))
+            .header("Content-Type", "application/json")
+            .json(data);
+        self.with_headers(request)
+    }
+
+    fn process_stream(
+        &self,
+        line: &str,
+        content: &mut ChatContent,
+    ) -> Result<Option<String>, ConverseError> {
+        let Some(data) = sse_data(line) else {
+            return Ok(None);
+        };
+        if let Some(message) = error_message(data) {
+            return Err(ConverseError::Provider(message));
+        }
+        let chunk: serde_json::Value = serde_json::from_str(data)?;
+        // Only sent in the last chunk, which has no choices.
+        if let Some(usage) = Usage::from_json(&chunk["usage"], "prompt_tokens", "completion_tokens")
+        {
+            content.usage = Some(usage);
+        }
+        if let Some(reason) = chunk
+            .pointer("/choices/0/finish_reason")
+            .and_then(|val| val.as_str())
+        {
+            content.stop_reason = reason.to_string();
+            content.truncated = reason == "length";
+        }
+        // OpenAI keeps the reasoning to itself, but compatible servers stream it in
+        // one of these.
+        let delta = &chunk["choices"][0]["delta"];
+        if let Some(thinking) = delta["reasoning_content"]
+            .as_str()
+            .or_else(|| delta["reasoning"].as_str())
+        {
+            content.thinking.push_str(thinking);
+        }
+        // Calls are streamed in pieces, with the index of the call each piece belongs to.
+        let tool_calls = chunk.pointer("/choices/0/delta/tool_calls");
+        for delta in tool_calls
+            .and_then(|calls| calls.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let index = delta["index"].as_u64().unwrap_or_default() as usize;
+            if content.tool_calls.len() <= index {
+                content.tool_calls.resize_with(index + 1, ToolCall::default);
+            }
+            let call = &mut content.tool_calls[index];
+            if let Some(id) = delta["id"].as_str() {
+                call.id = id.to_string();
+            }
+            if let Some(name) = delta
+                .pointer("/function/name")
+                .and_then(|name| name.as_str())
+            {
+                call.name.push_str(name);
+            }
+            if let Some(arguments) = delta
+                .pointer("/function/arguments")
+                .and_then(|arguments| arguments.as_str())
+            {
+                call.arguments.push_str(arguments);
+            }
+        }
+        Ok(chunk
+            .pointer("/choices/0/delta/content")
+            .and_then(|val| val.as_str())
+            .map(str::to_string))
+    }
+
+    fn parse_error(&self, response: &str) -> Option<String> {
+        error_message(response)
+    }
+
+    fn model_list_request(&self, client: &Client) -> Option<RequestBuilder> {
+        Some(self.with_headers(client.get(format!("{}/models", self.base_url))))
+    }
+
+    fn parse_model_list(&self, response: &str) -> Vec<String> {
+        let response_content: serde_json::Value =
+            serde_json::from_str(response).unwrap_or_default();
+        response_content["data"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|model| model["id"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::OpenAI;
+    use crate::{
+        models::{request, test_server, ChatContent},
+        parser::config::ConfigCustom,
+    };
+
+    const STREAM: &str = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"choices\":[{\"delta\":{\"content\":\" there\"},\"finish_reason\":\"stop\"}]}\n\n\
+        data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":3}}\n\n\
+        data: [DONE]\n\n";
+
+    // Sends one question to a stand-in endpoint, returning the answer and the raw request.
+    async fn ask(config: ConfigCustom) -> (ChatContent, String) {
+        let (url, server) = test_server::serve("text/event-stream", STREAM).await;
+        let provider = OpenAI::custom(&ConfigCustom {
+            base_url: format!("{}/v1/", url),
+            ..config
+        });
+        let query = json!({"role": "user", "text": "Hi"});
+        let content = request(
+            &provider,
+            "local-model",
+            &query,
+            &json!({"chat": []}),
+            &[],
+            1,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        (content, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn streams_from_a_custom_endpoint() {
+        let (content, request) = ask(ConfigCustom {
+            api: "secret".to_string(),
+            headers: HashMap::from([("X-Title".to_string(), "converse".to_string())]),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(content.answer, "Hello there");
+        assert_eq!(content.stop_reason, "stop");
+        let usage = content.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (12, 3));
+
+        let headers = request.to_lowercase();
+        assert!(request.starts_with("POST /v1/chat/completions "));
+        assert!(headers.contains("authorization: bearer secret\r\n"));
+        assert!(headers.contains("x-title: converse\r\n"));
+        assert!(request.contains(r#""model":"local-model""#));
+    }
 
-        template
+    #[tokio::test]
+    async fn leaves_out_authorization_without_a_key() {
+        let (content, request) = ask(ConfigCustom::default()).await;
+        assert_eq!(content.answer, "Hello there");
+        assert!(!request.to_lowercase().contains("authorization:"));
     }
 }
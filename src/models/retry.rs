@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+
+use crate::parser::{
+    config::ConfigRetry,
+    time::{self, parse_http_date, parse_rfc3339},
+};
+
+// Rate limits and overloaded servers are worth another try, other errors are not.
+pub fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Exponential backoff, with jitter so that clients don't all retry at once.
+pub fn backoff(policy: &ConfigRetry, attempt: u32) -> Duration {
+    let delay = policy.initial_delay * 2f64.powi(attempt.saturating_sub(1) as i32);
+    let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+    Duration::try_from_secs_f64(delay.min(policy.max_delay) * jitter).unwrap_or_default()
+}
+
+// The `Retry-After` header, given in seconds or as an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse() {
+        Ok(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+        Err(_) => Some(until(parse_http_date(value)?)),
+    }
+}
+
+// Time left until an RFC 3339 timestamp header, such as the ratelimit resets sent by Anthropic.
+pub fn until_header_time(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    Some(until(parse_rfc3339(headers.get(name)?.to_str().ok()?)?))
+}
+
+// Time left until a moment in seconds since the epoch, zero once it has passed.
+fn until(time: i64) -> Duration {
+    Duration::from_secs(time.saturating_sub(time::now()).max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn policy() -> ConfigRetry {
+        ConfigRetry {
+            max_attempts: 5,
+            initial_delay: 1.0,
+            max_delay: 5.0,
+        }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        for (attempt, full) in [(1, 1.0), (2, 2.0), (3, 4.0)] {
+            let delay = backoff(&policy(), attempt).as_secs_f64();
+            assert!((full * 0.5..=full).contains(&delay), "{attempt}: {delay}");
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_delay() {
+        let delay = backoff(&policy(), 10).as_secs_f64();
+        assert!((2.5..=5.0).contains(&delay), "{delay}");
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after(&headers(" 1.5 ")),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        let delay = retry_after(&headers("Fri, 01 Jan 2100 00:00:00 GMT")).unwrap();
+        assert!(delay > Duration::from_secs(365 * 86400));
+    }
+
+    #[test]
+    fn retry_after_rejects_other_values() {
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+}
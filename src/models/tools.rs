@@ -0,0 +1,270 @@
+mod calculator;
+pub mod mcp;
+pub mod shell;
+
+use std::{fs, future::Future, path::PathBuf, pin::Pin};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use shell::{CommandRequest, Shell};
+
+use super::ThinkingBlock;
+
+use crate::parser::{
+    config::{Config, ConfigFiles},
+    time,
+};
+
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>>;
+
+// A function the model can call. `parameters` is a JSON schema for its arguments.
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+pub trait Tool: Send + Sync {
+    fn spec(&self) -> &ToolSpec;
+
+    /// Runs the tool, returning its output or an error message for the model.
+    fn call(&self, arguments: serde_json::Value) -> ToolFuture<'_>;
+}
+
+// A call requested by the model. The arguments are kept as the JSON text the
+// model produced, since some providers stream them in pieces.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ToolCall {
+    // Arguments that are not a JSON object are sent back as an empty one.
+    pub fn arguments(&self) -> serde_json::Value {
+        serde_json::from_str(&self.arguments)
+            .ok()
+            .filter(serde_json::Value::is_object)
+            .unwrap_or_else(|| json!({}))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolResult {
+    pub id: String,
+    pub name: String,
+    pub output: String,
+    pub is_error: bool,
+}
+
+// The calls the model made in one response, with the text before them and their results.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolRound {
+    pub text: String,
+    pub thinking: Vec<ThinkingBlock>,
+    pub calls: Vec<ToolCall>,
+    pub results: Vec<ToolResult>,
+}
+
+// The tools enabled in `[tools]`, followed by those of the connected MCP servers.
+pub fn registry(
+    config: &Config,
+    approvals: &async_channel::Sender<CommandRequest>,
+) -> Vec<Box<dyn Tool>> {
+    let mut tools: Vec<Box<dyn Tool>> = vec![
+        Box::new(CurrentTime::new()),
+        Box::new(Calculator::new()),
+        Box::new(Shell::new(&config.tools.shell, approvals)),
+    ];
+    // Without a directory to read from, the tool could only fail.
+    if !config.tools.read_file_dirs.is_empty() {
+        tools.push(Box::new(ReadFile::new(
+            &config.tools.read_file_dirs,
+            &config.files,
+        )));
+    }
+    tools.retain(|tool| config.tools.enabled.contains(&tool.spec().name));
+    for tool in mcp::tools() {
+        // Calls are matched by name, so a name that is already taken would never be called.
+        if tools
+            .iter()
+            .any(|other| other.spec().name == tool.spec().name)
+        {
+            eprintln!(
+                "Skipping the MCP tool {}, its name is taken.",
+                tool.spec().name
+            );
+        } else {
+            tools.push(tool);
+        }
+    }
+    tools
+}
+
+pub async fn call(tools: &[Box<dyn Tool>], call: &ToolCall) -> ToolResult {
+    let output = match tools.iter().find(|tool| tool.spec().name == call.name) {
+        Some(tool) => tool.call(call.arguments()).await,
+        None => Err(format!("There is no tool named {}.", call.name)),
+    };
+    let is_error = output.is_err();
+    ToolResult {
+        id: call.id.clone(),
+        name: call.name.clone(),
+        output: output.unwrap_or_else(|error| error),
+        is_error,
+    }
+}
+
+struct CurrentTime {
+    spec: ToolSpec,
+}
+
+impl CurrentTime {
+    fn new() -> Self {
+        Self {
+            spec: ToolSpec {
+                name: "current_time".to_string(),
+                description: "Returns the current date and time in UTC.".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+            },
+        }
+    }
+}
+
+impl Tool for CurrentTime {
+    fn spec(&self) -> &ToolSpec {
+        &self.spec
+    }
+
+    fn call(&self, _arguments: serde_json::Value) -> ToolFuture<'_> {
+        let now = time::now();
+        let (year, month, day) = time::civil_from_days(now.div_euclid(86400));
+        let seconds = now.rem_euclid(86400);
+        Box::pin(std::future::ready(Ok(format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        ))))
+    }
+}
+
+struct Calculator {
+    spec: ToolSpec,
+}
+
+impl Calculator {
+    fn new() -> Self {
+        Self {
+            spec: ToolSpec {
+                name: "calculator".to_string(),
+                description: "Evaluates an arithmetic expression. Supports + - * / % ^, \
+                    parentheses, sqrt, abs, ln, log, exp, sin, cos, tan, round, floor, ceil, \
+                    pi and e."
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {"type": "string", "description": "For example 2 * (3 + 4) ^ 2"}
+                    },
+                    "required": ["expression"]
+                }),
+            },
+        }
+    }
+}
+
+impl Tool for Calculator {
+    fn spec(&self) -> &ToolSpec {
+        &self.spec
+    }
+
+    fn call(&self, arguments: serde_json::Value) -> ToolFuture<'_> {
+        let result = match arguments["expression"].as_str() {
+            Some(expression) => calculator::evaluate(expression).map(|value| value.to_string()),
+            None => Err("expression is missing.".to_string()),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+// Reads text files, but only inside the directories listed in `read_file_dirs`.
+struct ReadFile {
+    spec: ToolSpec,
+    dirs: Vec<PathBuf>,
+    max_file_size: u64,
+}
+
+impl ReadFile {
+    fn new(dirs: &[PathBuf], limits: &ConfigFiles) -> Self {
+        let names: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+        Self {
+            spec: ToolSpec {
+                name: "read_file".to_string(),
+                description: format!(
+                    "Reads a text file. Only files inside these directories can be read: {}",
+                    names.join(", ")
+                ),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "Absolute path of the file"}
+                    },
+                    "required": ["path"]
+                }),
+            },
+            dirs: dirs.to_vec(),
+            max_file_size: limits.max_file_size,
+        }
+    }
+
+    fn read(&self, path: &str) -> Result<String, String> {
+        // Resolving the path first keeps `..` and symlinks from leaving the directories.
+        let path = fs::canonicalize(path).map_err(|error| format!("{}: {}", path, error))?;
+        let allowed = self
+            .dirs
+            .iter()
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .any(|dir| path.starts_with(dir));
+        if !allowed {
+            return Err(format!(
+                "{} is outside the allowed directories.",
+                path.display()
+            ));
+        }
+        let size = fs::metadata(&path)
+            .map_err(|error| error.to_string())?
+            .len();
+        if size > self.max_file_size * 1024 {
+            return Err(format!(
+                "{} is larger than {} KiB.",
+                path.display(),
+                self.max_file_size
+            ));
+        }
+        fs::read_to_string(&path).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+}
+
+impl Tool for ReadFile {
+    fn spec(&self) -> &ToolSpec {
+        &self.spec
+    }
+
+    fn call(&self, arguments: serde_json::Value) -> ToolFuture<'_> {
+        let result = match arguments["path"].as_str() {
+            Some(path) => self.read(path),
+            None => Err("path is missing.".to_string()),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
@@ -0,0 +1,222 @@
+// Evaluates arithmetic expressions for the calculator tool, e.g. "2 * (3 + 4) ^ 2".
+
+// Parentheses, signs and exponents recurse, so deep nesting could overflow the stack.
+const MAX_DEPTH: usize = 100;
+
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    match parser.peek() {
+        None if value.is_finite() => Ok(value),
+        None => Err("The result is not a finite number.".to_string()),
+        Some(c) => Err(format!(
+            "Unexpected '{}' at position {}.",
+            c, parser.position
+        )),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                value /= self.unary()?;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("The expression is nested too deeply.".to_string());
+        }
+        self.depth += 1;
+        let value = self.signed();
+        self.depth -= 1;
+        value
+    }
+
+    fn signed(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            Ok(-self.unary()?)
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    // Right associative, so 2^3^2 is 2^9.
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            Ok(base.powf(self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.eat('(') {
+            let value = self.expression()?;
+            return if self.eat(')') {
+                Ok(value)
+            } else {
+                Err("Missing ')'.".to_string())
+            };
+        }
+        let start = self.position;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                number
+                    .parse()
+                    .map_err(|_| format!("{} is not a number.", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+                    self.position += 1;
+                }
+                let name: String = self.chars[start..self.position].iter().collect();
+                self.function(&name)
+            }
+            Some(c) => Err(format!("Unexpected '{}' at position {}.", c, start)),
+            None => Err("The expression ended too early.".to_string()),
+        }
+    }
+
+    fn function(&mut self, name: &str) -> Result<f64, String> {
+        match name {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+        let function: fn(f64) -> f64 = match name {
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            "ln" => f64::ln,
+            "log" => f64::log10,
+            "exp" => f64::exp,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "round" => f64::round,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            _ => return Err(format!("Unknown function or constant {}.", name)),
+        };
+        if !self.eat('(') {
+            return Err(format!("{} needs its argument in parentheses.", name));
+        }
+        let argument = self.expression()?;
+        if !self.eat(')') {
+            return Err("Missing ')'.".to_string());
+        }
+        Ok(function(argument))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14.0));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("7 % 4 * 2"), Ok(6.0));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(evaluate("2 * (3 + 4) ^ 2"), Ok(98.0));
+    }
+
+    #[test]
+    fn powers_are_right_associative() {
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("2 ^ -1"), Ok(0.5));
+    }
+
+    #[test]
+    fn calls_functions_and_constants() {
+        assert_eq!(evaluate("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(evaluate("round(2.5) + floor(1.9) + ceil(1.1)"), Ok(6.0));
+        assert_eq!(evaluate("log(1000)"), Ok(3.0));
+        assert_eq!(evaluate("cos(pi)"), Ok(-1.0));
+        assert_eq!(evaluate("ln(e)"), Ok(1.0));
+        assert!(evaluate("foo(1)").is_err());
+        assert!(evaluate("sqrt 4").is_err());
+    }
+
+    #[test]
+    fn rejects_infinite_results() {
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("0 / 0").is_err());
+        assert!(evaluate("sqrt(-1)").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert!(evaluate("").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 + 2)").is_err());
+        assert!(evaluate("1..2").is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(evaluate(&nested(99)), Ok(1.0));
+        assert_eq!(
+            evaluate(&nested(100)),
+            Err("The expression is nested too deeply.".to_string())
+        );
+        assert!(evaluate(&nested(100_000)).is_err());
+        assert!(evaluate(&"-".repeat(100_000)).is_err());
+        assert!(evaluate(&format!("2{}", "^2".repeat(100_000))).is_err());
+    }
+}
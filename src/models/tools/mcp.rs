@@ -0,0 +1,402 @@
+// A client for Model Context Protocol servers, which offer tools over JSON-RPC on stdio.
+
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::oneshot,
+};
+
+use crate::parser::config::ConfigMcpServer;
+
+use super::{Tool, ToolFuture, ToolSpec};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// Servers that finished the handshake. Their tools are offered to every provider.
+static SERVERS: Mutex<Vec<Arc<Server>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone)]
+pub enum McpStatus {
+    // The names of the tools the server offers.
+    Connected(Vec<String>),
+    Failed(String),
+    Stopped,
+}
+
+type Response = Result<serde_json::Value, String>;
+type Output = Lines<BufReader<ChildStdout>>;
+
+struct Server {
+    name: String,
+    // Taken to reap the process once it exits on its own.
+    child: Mutex<Option<Child>>,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    responses: Mutex<HashMap<u64, oneshot::Sender<Response>>>,
+    next_id: AtomicU64,
+    stopped: AtomicBool,
+    timeout: Duration,
+    tools: OnceLock<Vec<ToolSpec>>,
+}
+
+// Starts the server and lists its tools, reporting when it connects and when it stops.
+pub async fn connect(config: ConfigMcpServer, on_status: impl Fn(McpStatus) + Send + 'static) {
+    match start(&config).await {
+        Ok((server, output)) => {
+            let names = server
+                .tools()
+                .iter()
+                .map(|tool| tool.name.clone())
+                .collect();
+            SERVERS.lock().unwrap().push(server.clone());
+            on_status(McpStatus::Connected(names));
+            read_messages(&server, output).await;
+            on_status(McpStatus::Stopped);
+        }
+        Err(error) => on_status(McpStatus::Failed(error)),
+    }
+}
+
+// Kills the servers, which would otherwise outlive converse.
+pub fn shutdown() {
+    for server in SERVERS.lock().unwrap().drain(..) {
+        server.kill();
+    }
+}
+
+// The tools of the servers that are still running.
+pub fn tools() -> Vec<Box<dyn Tool>> {
+    SERVERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|server| !server.stopped.load(Ordering::Relaxed))
+        .flat_map(|server| {
+            server.tools().iter().map(|spec| {
+                Box::new(McpTool {
+                    spec: spec.clone(),
+                    server: server.clone(),
+                }) as Box<dyn Tool>
+            })
+        })
+        .collect()
+}
+
+async fn start(config: &ConfigMcpServer) -> Result<(Arc<Server>, Output), String> {
+    let mut child = Command::new(&config.command)
+        .args(&config.args)
+        .envs(&config.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| format!("Couldn't start {}: {}", config.command, error))?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let server = Arc::new(Server {
+        name: config.name.clone(),
+        child: Mutex::new(Some(child)),
+        stdin: tokio::sync::Mutex::new(stdin),
+        responses: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(1),
+        stopped: AtomicBool::new(false),
+        timeout: Duration::from_secs(config.timeout),
+        tools: OnceLock::new(),
+    });
+
+    let mut output = BufReader::new(stdout).lines();
+    let handshake = async {
+        let params = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": {"name": "converse", "version": env!("CARGO_PKG_VERSION")}
+        });
+        let result = server
+            .handshake_request("initialize", params, &mut output)
+            .await?;
+        if !result["protocolVersion"].is_string() {
+            return Err("The server did not answer the handshake.".to_string());
+        }
+        server.notify("notifications/initialized").await?;
+
+        let mut tools = Vec::new();
+        let mut cursor = serde_json::Value::Null;
+        loop {
+            let params = if cursor.is_null() {
+                json!({})
+            } else {
+                json!({ "cursor": cursor })
+            };
+            let result = server
+                .handshake_request("tools/list", params, &mut output)
+                .await?;
+            tools.extend(result["tools"].as_array().into_iter().flatten().map(spec));
+            cursor = result["nextCursor"].clone();
+            if cursor.is_null() {
+                return Ok(tools);
+            }
+        }
+    };
+    let tools = handshake.await?;
+    server.tools.set(tools).ok();
+    Ok((server, output))
+}
+
+fn spec(tool: &serde_json::Value) -> ToolSpec {
+    let parameters = if tool["inputSchema"].is_object() {
+        tool["inputSchema"].clone()
+    } else {
+        json!({"type": "object", "properties": {}})
+    };
+    ToolSpec {
+        name: tool["name"].as_str().unwrap_or_default().to_string(),
+        description: tool["description"].as_str().unwrap_or_default().to_string(),
+        parameters,
+    }
+}
+
+// Hands responses to the requests waiting for them until the server exits.
+async fn read_messages(server: &Server, mut output: Output) {
+    while let Ok(Some(line)) = output.next_line().await {
+        if let Some(reply) = server.handle_message(&line) {
+            server.write(&reply).await.ok();
+        }
+    }
+    server.stopped.store(true, Ordering::Relaxed);
+    let child = server.child.lock().unwrap().take();
+    if let Some(mut child) = child {
+        child.wait().await.ok();
+    }
+    for (_, sender) in server.responses.lock().unwrap().drain() {
+        sender
+            .send(Err(format!("{} has stopped.", server.name)))
+            .ok();
+    }
+}
+
+impl Server {
+    fn tools(&self) -> &[ToolSpec] {
+        self.tools.get().map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn kill(&self) {
+        if let Some(child) = self.child.lock().unwrap().as_mut() {
+            child.start_kill().ok();
+        }
+    }
+
+    async fn write(&self, message: &serde_json::Value) -> Result<(), String> {
+        let mut stdin = self.stdin.lock().await;
+        let line = format!("{}\n", message);
+        stdin
+            .write_all(line.as_bytes())
+            .await
+            .and(stdin.flush().await)
+            .map_err(|error| format!("{} has stopped: {}", self.name, error))
+    }
+
+    async fn notify(&self, method: &str) -> Result<(), String> {
+        self.write(&json!({"jsonrpc": "2.0", "method": method}))
+            .await
+    }
+
+    // The request is registered before it is sent, so its response can't arrive unclaimed.
+    async fn send_request(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<(u64, oneshot::Receiver<Response>), String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.responses.lock().unwrap().insert(id, sender);
+        self.write(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))
+            .await?;
+        Ok((id, receiver))
+    }
+
+    // Nothing else reads the output until the handshake is done, so this does.
+    async fn handshake_request(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+        output: &mut Output,
+    ) -> Response {
+        let (id, mut receiver) = self.send_request(method, params).await?;
+        let read = async {
+            loop {
+                match output.next_line().await {
+                    Ok(Some(line)) => {
+                        if let Some(reply) = self.handle_message(&line) {
+                            self.write(&reply).await?;
+                        }
+                        if let Ok(response) = receiver.try_recv() {
+                            return response;
+                        }
+                    }
+                    _ => return Err(format!("{} exited during the handshake.", self.name)),
+                }
+            }
+        };
+        let response = tokio::time::timeout(self.timeout, read).await;
+        self.responses.lock().unwrap().remove(&id);
+        response.map_err(|_| format!("{} did not answer in time.", self.name))?
+    }
+
+    async fn request(&self, method: &str, params: serde_json::Value) -> Response {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Err(format!("{} has stopped.", self.name));
+        }
+        let (id, receiver) = self.send_request(method, params).await?;
+        let response = tokio::time::timeout(self.timeout, receiver).await;
+        self.responses.lock().unwrap().remove(&id);
+        match response {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(format!("{} has stopped.", self.name)),
+            Err(_) => Err(format!("{} did not answer in time.", self.name)),
+        }
+    }
+
+    // Returns the reply to send, when the message is a request from the server.
+    fn handle_message(&self, line: &str) -> Option<serde_json::Value> {
+        let message: serde_json::Value = serde_json::from_str(line).ok()?;
+        let id = message.get("id")?;
+        match message["method"].as_str() {
+            Some("ping") => Some(json!({"jsonrpc": "2.0", "id": id, "result": {}})),
+            Some(method) => Some(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": -32601, "message": format!("{} is not supported.", method)}
+            })),
+            None => {
+                let sender = self.responses.lock().unwrap().remove(&id.as_u64()?)?;
+                let response = match message.get("error") {
+                    Some(error) => Err(error["message"]
+                        .as_str()
+                        .unwrap_or("The server returned an error.")
+                        .to_string()),
+                    None => Ok(message["result"].clone()),
+                };
+                sender.send(response).ok();
+                None
+            }
+        }
+    }
+}
+
+struct McpTool {
+    spec: ToolSpec,
+    server: Arc<Server>,
+}
+
+impl Tool for McpTool {
+    fn spec(&self) -> &ToolSpec {
+        &self.spec
+    }
+
+    fn call(&self, arguments: serde_json::Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let result = self
+                .server
+                .request(
+                    "tools/call",
+                    json!({"name": self.spec.name, "arguments": arguments}),
+                )
+                .await?;
+            // Only text is passed on to the model.
+            let output: Vec<&str> = result["content"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|content| match content["type"].as_str() {
+                    Some("text") => content["text"].as_str().unwrap_or_default(),
+                    Some("resource") => content["resource"]["text"]
+                        .as_str()
+                        .unwrap_or("[binary resource]"),
+                    _ => "[unsupported content]",
+                })
+                .collect();
+            if result["isError"] == true {
+                Err(output.join("\n"))
+            } else {
+                Ok(output.join("\n"))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers the handshake with two pages of tools, then one tool call.
+    const SERVER: &str = r#"
+read line
+echo '{"jsonrpc":"2.0","id":1,"result":{"protocolVersion":"2024-11-05","capabilities":{}}}'
+read line
+case "$line" in *notifications/initialized*) ;; *) exit 1 ;; esac
+read line
+echo '{"jsonrpc":"2.0","id":2,"result":{"tools":[{"name":"first","description":"The first page."}],"nextCursor":"page-2"}}'
+read line
+case "$line" in *'"cursor":"page-2"'*) ;; *) exit 1 ;; esac
+echo '{"jsonrpc":"2.0","id":3,"result":{"tools":[{"name":"second","inputSchema":{"type":"object","properties":{"x":{"type":"number"}}}}]}}'
+read line
+echo '{"jsonrpc":"2.0","id":4,"result":{"content":[{"type":"text","text":"called"}]}}'
+read line
+"#;
+
+    fn config(script: &str) -> ConfigMcpServer {
+        ConfigMcpServer {
+            name: "stub".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: HashMap::new(),
+            timeout: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_tools_across_pages() {
+        let (server, output) = start(&config(SERVER)).await.unwrap();
+        let names: Vec<&str> = server
+            .tools()
+            .iter()
+            .map(|tool| tool.name.as_str())
+            .collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(server.tools()[0].description, "The first page.");
+        assert_eq!(server.tools()[0].parameters["type"], "object");
+        assert!(server.tools()[1].parameters["properties"]["x"].is_object());
+
+        let reader = tokio::spawn({
+            let server = server.clone();
+            async move { read_messages(&server, output).await }
+        });
+        let tool = McpTool {
+            spec: server.tools()[0].clone(),
+            server: server.clone(),
+        };
+        assert_eq!(tool.call(json!({})).await, Ok("called".to_string()));
+
+        server.kill();
+        reader.await.unwrap();
+        assert!(server.stopped.load(Ordering::Relaxed));
+        assert!(tool.call(json!({})).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_server_exits() {
+        let error = start(&config("read line")).await.err().unwrap();
+        assert_eq!(error, "stub exited during the handshake.");
+    }
+}
@@ -0,0 +1,231 @@
+// Runs shell commands, each one only after the user approves it.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::oneshot,
+};
+
+use crate::parser::{config::ConfigShell, files};
+
+use super::{Tool, ToolFuture, ToolSpec};
+
+// Sent to the UI for every command. Dropping `reply` denies it.
+pub struct CommandRequest {
+    pub command: String,
+    pub working_dir: PathBuf,
+    pub reply: oneshot::Sender<bool>,
+}
+
+// Control operators, redirections and substitutions could make an allowed command run
+// something else entirely, so commands with them always need approval.
+pub fn has_operators(command: &str) -> bool {
+    command.contains([';', '&', '|', '`', '$', '(', ')', '<', '>', '\n'])
+}
+
+// Longer commands are always asked about, as nobody could review them against a pattern.
+const MAX_ALLOWED_LENGTH: usize = 4096;
+
+// Patterns only look at the command, so an allowed command runs in any directory.
+pub fn is_allowed(patterns: &[String], command: &str) -> bool {
+    let command = command.trim();
+    command.len() <= MAX_ALLOWED_LENGTH
+        && !has_operators(command)
+        && patterns
+            .iter()
+            .any(|pattern| files::glob_match(pattern.trim(), command))
+}
+
+// What "Always allow" suggests: the program with any arguments, e.g. "git *". None for
+// commands that could never be allowed.
+pub fn default_pattern(command: &str) -> Option<String> {
+    let command = command.trim();
+    if has_operators(command) {
+        return None;
+    }
+    Some(match command.split_once(char::is_whitespace) {
+        Some((program, _)) => format!("{} *", program),
+        None => command.to_string(),
+    })
+}
+
+pub struct Shell {
+    spec: ToolSpec,
+    config: ConfigShell,
+    approvals: async_channel::Sender<CommandRequest>,
+}
+
+impl Shell {
+    pub fn new(config: &ConfigShell, approvals: &async_channel::Sender<CommandRequest>) -> Self {
+        Self {
+            spec: ToolSpec {
+                name: "shell".to_string(),
+                description: format!(
+                    "Runs a command with sh on the user's machine, after they approve it, and \
+                    returns its output. Commands are stopped after {} seconds.",
+                    config.timeout
+                ),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {"type": "string"},
+                        "working_directory": {
+                            "type": "string",
+                            "description": format!("Defaults to {}", config.working_dir.display())
+                        }
+                    },
+                    "required": ["command"]
+                }),
+            },
+            config: config.clone(),
+            approvals: approvals.clone(),
+        }
+    }
+
+    async fn run(&self, command: &str, working_dir: &Path) -> Result<String, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(working_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|error| format!("Couldn't run the command: {}", error))?;
+        let limit = self.config.max_output * 1024;
+        let stdout = read_limited(child.stdout.take().unwrap(), limit);
+        let stderr = read_limited(child.stderr.take().unwrap(), limit);
+        let finished = async { tokio::join!(child.wait(), stdout, stderr) };
+
+        match tokio::time::timeout(Duration::from_secs(self.config.timeout), finished).await {
+            Ok((status, stdout, stderr)) => {
+                let mut output = stdout;
+                if !stderr.is_empty() {
+                    output.push_str(&format!("\n[stderr]\n{}", stderr));
+                }
+                match status.map(|status| status.code()) {
+                    Ok(Some(0)) => Ok(output),
+                    Ok(Some(code)) => Ok(format!("{}\n[exit code {}]", output, code)),
+                    Ok(None) => Err(format!("{}\n[stopped by a signal]", output)),
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+            Err(_) => Err(format!(
+                "The command was stopped after {} seconds.",
+                self.config.timeout
+            )),
+        }
+    }
+}
+
+// Output past the limit is read and thrown away, so the command doesn't block on a full pipe.
+async fn read_limited(mut reader: impl AsyncRead + Unpin, limit: u64) -> String {
+    let mut bytes = Vec::new();
+    (&mut reader).take(limit).read_to_end(&mut bytes).await.ok();
+    let skipped = tokio::io::copy(&mut reader, &mut tokio::io::sink())
+        .await
+        .unwrap_or_default();
+    let mut output = String::from_utf8_lossy(&bytes).to_string();
+    if skipped > 0 {
+        output.push_str(&format!("\n[{} more bytes were cut]", skipped));
+    }
+    output
+}
+
+impl Tool for Shell {
+    fn spec(&self) -> &ToolSpec {
+        &self.spec
+    }
+
+    fn call(&self, arguments: serde_json::Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let Some(command) = arguments["command"].as_str() else {
+                return Err("command is missing.".to_string());
+            };
+            let working_dir = match arguments["working_directory"].as_str() {
+                Some(dir) => self.config.working_dir.join(dir),
+                None => self.config.working_dir.clone(),
+            };
+            if !working_dir.is_dir() {
+                return Err(format!("{} is not a directory.", working_dir.display()));
+            }
+
+            let (reply, approved) = oneshot::channel();
+            let request = CommandRequest {
+                command: command.to_string(),
+                working_dir: working_dir.clone(),
+                reply,
+            };
+            self.approvals
+                .send(request)
+                .await
+                .map_err(|_| "Commands can't be approved right now.".to_string())?;
+            if approved.await != Ok(true) {
+                return Err("The user did not allow running this command.".to_string());
+            }
+            self.run(command, &working_dir).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_matching_commands() {
+        let allow = patterns(&["git *", " ls ", "cargo ?est"]);
+        assert!(is_allowed(&allow, "git status"));
+        assert!(is_allowed(&allow, "  ls"));
+        assert!(is_allowed(&allow, "cargo test"));
+        assert!(!is_allowed(&allow, "gitk"));
+        assert!(!is_allowed(&allow, "ls -la"));
+        assert!(!is_allowed(&[], "ls"));
+    }
+
+    #[test]
+    fn always_asks_about_long_commands() {
+        let allow = patterns(&["*x", "echo *"]);
+        let command = format!("echo {}", "y".repeat(60_000));
+        assert!(!is_allowed(&allow, &command));
+        assert!(!is_allowed(&allow, &format!("{}x", command)));
+        assert!(is_allowed(&allow, "echo yyyx"));
+    }
+
+    #[test]
+    fn always_asks_about_operators() {
+        let allow = patterns(&["*"]);
+        for command in [
+            "git status; rm -rf ~",
+            "ls && rm x",
+            "ls | sh",
+            "echo `id`",
+            "echo $(id)",
+            "cat < /etc/passwd",
+            "ls > out",
+            "ls\nrm x",
+        ] {
+            assert!(!is_allowed(&allow, command), "{command}");
+        }
+    }
+
+    #[test]
+    fn suggests_the_program_with_any_arguments() {
+        assert_eq!(default_pattern(" git log -3"), Some("git *".to_string()));
+        assert_eq!(default_pattern("ls"), Some("ls".to_string()));
+        assert_eq!(default_pattern("ls; rm x"), None);
+        assert_eq!(default_pattern("cat a | sh"), None);
+    }
+}
@@ -1,65 +1,322 @@
 use std::{
     fs::{self},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde_json::json;
 
-use crate::models::ChatContent;
+use crate::{
+    error::ConverseError,
+    models::{ChatContent, Image, Selection},
+};
+
+use super::{config::ConfigGeneration, files::ContextFile, time};
 
 pub struct Cache {}
 
 impl Cache {
     pub fn read(path: &PathBuf) -> serde_json::Value {
         if let Ok(cache_file) = fs::read_to_string(path) {
-            let response: serde_json::Value =
-                serde_json::from_str(&cache_file).unwrap_or(json!({"chat": []}));
+            let response: serde_json::Value = serde_json::from_str(&cache_file)
+                .ok()
+                .filter(|response: &serde_json::Value| response["chat"].is_array())
+                .unwrap_or(json!({"chat": []}));
             response
         } else {
             json!({"chat": []})
         }
     }
 
-    fn write(path: PathBuf, response: serde_json::Value) {
-        let cache_file = serde_json::to_string(&response).expect("Could not Serialize");
-        fs::write(path, cache_file).expect("Could not write.");
+    fn write(path: PathBuf, response: serde_json::Value) -> Result<(), ConverseError> {
+        let cache_file = serde_json::to_string(&response)?;
+        fs::write(path, cache_file)?;
+        Ok(())
     }
-    pub fn update_conversation(file: PathBuf, response: &ChatContent, model: &str) {
-        let new_question = json!(
-        {
-            "role": "user",
-            "text": response.question
-        });
-        let new_answer = json!(
+    pub fn update_conversation(
+        file: PathBuf,
+        response: &ChatContent,
+        selection: &Selection,
+    ) -> Result<(), ConverseError> {
+        let mut new_question = Self::question(response)?;
+        let mut new_answer = Self::answer_json(response)?;
+        // Both halves, so the history still alternates between the user and the model.
+        if response.blocked {
+            new_question["blocked"] = json!(true);
+        }
+        new_answer["time"] = json!(time::now());
+
+        let mut conversation = Self::read(&file);
+        // The default model is stored by name, so reopening the chat keeps using it.
+        if conversation.get("provider").is_none() {
+            conversation["provider"] = json!(selection.provider);
+            conversation["model"] = if selection.model.is_empty() {
+                json!(response.model)
+            } else {
+                json!(selection.model)
+            };
+        }
+        let chat = conversation["chat"].as_array_mut().ok_or_else(|| {
+            ConverseError::Decode(format!("{} has no chat history.", file.display()))
+        })?;
+        chat.push(new_question);
+        chat.push(new_answer);
+
+        Self::write(file, conversation)
+    }
+
+    // Replaces the last answer of the conversation, which has been continued.
+    pub fn replace_answer(file: PathBuf, response: &ChatContent) -> Result<(), ConverseError> {
+        let mut new_answer = Self::answer_json(response)?;
+        let mut conversation = Self::read(&file);
+        let chat = conversation["chat"].as_array_mut().ok_or_else(|| {
+            ConverseError::Decode(format!("{} has no chat history.", file.display()))
+        })?;
+        let Some(position) = chat.iter().rposition(|item| item["role"] == "model") else {
+            return Err(ConverseError::Decode(format!(
+                "{} has no answer to continue.",
+                file.display()
+            )));
+        };
+        new_answer["time"] = chat[position]["time"].clone();
+        chat[position] = new_answer;
+        if response.blocked && position > 0 {
+            chat[position - 1]["blocked"] = json!(true);
+        }
+
+        Self::write(file, conversation)
+    }
+
+    fn answer_json(response: &ChatContent) -> Result<serde_json::Value, ConverseError> {
+        let mut answer = json!(
         {
             "role": "model",
             "text": response.answer
         });
+        if !response.thinking.is_empty() {
+            answer["thinking"] = json!(response.thinking);
+        }
+        if response.interrupted {
+            answer["interrupted"] = json!(true);
+        }
+        if let Some(usage) = response.usage {
+            answer["usage"] = serde_json::to_value(usage)?;
+        }
+        if !response.model.is_empty() {
+            answer["model"] = json!(response.model);
+        }
+        if !response.tool_rounds.is_empty() {
+            answer["tools"] = serde_json::to_value(&response.tool_rounds)?;
+        }
+        if !response.sources.is_empty() {
+            answer["sources"] = serde_json::to_value(&response.sources)?;
+        }
+        if !response.searches.is_empty() {
+            answer["searches"] = json!(response.searches);
+        }
+        if !response.notice.is_empty() {
+            answer["notice"] = json!(response.notice);
+        }
+        if !response.stop_reason.is_empty() {
+            answer["stop_reason"] = json!(response.stop_reason);
+        }
+        if response.truncated {
+            answer["truncated"] = json!(true);
+        }
+        if response.blocked {
+            answer["blocked"] = json!(true);
+        }
+        Ok(answer)
+    }
 
-        let mut conversation = Self::read(&file);
-        conversation
+    // An answer read back from the history.
+    pub fn answer(item: &serde_json::Value) -> ChatContent {
+        let text = |key: &str| item[key].as_str().unwrap_or_default().to_string();
+        ChatContent {
+            answer: text("text"),
+            thinking: text("thinking"),
+            interrupted: item["interrupted"] == true,
+            usage: serde_json::from_value(item["usage"].clone()).ok(),
+            model: text("model"),
+            tool_rounds: serde_json::from_value(item["tools"].clone()).unwrap_or_default(),
+            sources: serde_json::from_value(item["sources"].clone()).unwrap_or_default(),
+            searches: serde_json::from_value(item["searches"].clone()).unwrap_or_default(),
+            notice: text("notice"),
+            blocked: item["blocked"] == true,
+            stop_reason: text("stop_reason"),
+            truncated: item["truncated"] == true,
+            ..Default::default()
+        }
+    }
+
+    // The question as it is stored in the history, with the names of its images.
+    pub fn question(response: &ChatContent) -> Result<serde_json::Value, ConverseError> {
+        let mut question = json!(
+        {
+            "role": "user",
+            "text": response.question
+        });
+        if !response.images.is_empty() {
+            question["images"] = json!(response.images);
+        }
+        if !response.files.is_empty() {
+            question["files"] = serde_json::to_value(&response.files)?;
+            question["context"] = serde_json::to_value(&response.context)?;
+        }
+        Ok(question)
+    }
+
+    // Sampling parameters set for this conversation only.
+    pub fn generation(conversation: &serde_json::Value) -> ConfigGeneration {
+        serde_json::from_value(conversation["generation"].clone()).unwrap_or_default()
+    }
+
+    // Replaces the provider's system prompt for this conversation when not empty.
+    pub fn system_prompt(conversation: &serde_json::Value) -> &str {
+        conversation["system_prompt"].as_str().unwrap_or_default()
+    }
+
+    // Settings left at their defaults are removed from the history.
+    pub fn set_settings(
+        file: &PathBuf,
+        generation: &ConfigGeneration,
+        system_prompt: &str,
+    ) -> Result<(), ConverseError> {
+        let mut conversation = Self::read(file);
+        let settings = conversation
             .as_object_mut()
-            .unwrap()
-            .entry("model")
-            .or_insert(json!(model));
-        conversation["chat"]
-            .as_array_mut()
-            .unwrap()
-            .push(new_question);
-        conversation["chat"]
-            .as_array_mut()
-            .unwrap()
-            .push(new_answer);
+            .ok_or_else(|| ConverseError::Decode(format!("{} is not a chat.", file.display())))?;
+        if *generation == ConfigGeneration::default() {
+            settings.remove("generation");
+        } else {
+            settings.insert("generation".to_string(), serde_json::to_value(generation)?);
+        }
+        if system_prompt.is_empty() {
+            settings.remove("system_prompt");
+        } else {
+            settings.insert("system_prompt".to_string(), json!(system_prompt));
+        }
+        Self::write(file.clone(), conversation)
+    }
+
+    // Replaces the contents of the files attached to the message at `index`.
+    pub fn set_context(
+        file: &PathBuf,
+        index: usize,
+        context: &[ContextFile],
+    ) -> Result<(), ConverseError> {
+        let mut conversation = Self::read(file);
+        let message = conversation["chat"]
+            .get_mut(index)
+            .filter(|message| message["files"].is_array())
+            .ok_or_else(|| {
+                ConverseError::Decode(format!(
+                    "{} has no attached files at message {}.",
+                    file.display(),
+                    index
+                ))
+            })?;
+        message["context"] = serde_json::to_value(context)?;
+        Self::write(file.clone(), conversation)
+    }
 
-        Self::write(file, conversation);
+    // Older history files only store the provider, in the `model` field.
+    pub fn selection(conversation: &serde_json::Value) -> Option<Selection> {
+        let model = conversation["model"].as_str();
+        match conversation["provider"].as_str() {
+            Some(provider) => Some(Selection {
+                provider: provider.to_string(),
+                model: model.unwrap_or_default().to_string(),
+            }),
+            None => model.map(|provider| Selection {
+                provider: provider.to_string(),
+                model: String::new(),
+            }),
+        }
+    }
+
+    // Attached images are shared by all conversations, and referenced by name.
+    pub fn attachments_dir(history_path: &Path) -> PathBuf {
+        history_path.join("attachments")
+    }
+
+    pub fn save_image(history_path: &Path, bytes: &[u8]) -> Result<String, ConverseError> {
+        let extension = match Image::mime_type_of(bytes) {
+            Some("image/png") => "png",
+            Some(_) => "jpg",
+            None => {
+                return Err(ConverseError::Config(
+                    "Only PNG and JPEG images can be attached.".to_string(),
+                ))
+            }
+        };
+        let dir = Self::attachments_dir(history_path);
+        fs::create_dir_all(&dir)?;
+        let name = format!("{}.{}", time::now_nanos(), extension);
+        fs::write(dir.join(&name), bytes)?;
+        Ok(name)
+    }
+
+    pub fn load_image(history_path: &Path, name: &str) -> Result<Image, ConverseError> {
+        let bytes = fs::read(Self::attachments_dir(history_path).join(name))?;
+        let mime_type = Image::mime_type_of(&bytes).ok_or_else(|| {
+            ConverseError::Decode(format!("{} is not a PNG or JPEG image.", name))
+        })?;
+        Ok(Image::new(mime_type, &bytes))
+    }
+
+    // Replaces the image names in the history with their contents.
+    // Images that can't be read anymore are left out.
+    pub fn load_images(history_path: &Path, conversation: &mut serde_json::Value) {
+        for item in conversation["chat"].as_array_mut().into_iter().flatten() {
+            let Some(names) = item["images"].as_array() else {
+                continue;
+            };
+            let images: Vec<Image> = names
+                .iter()
+                .filter_map(|name| Self::load_image(history_path, name.as_str()?).ok())
+                .collect();
+            item["images"] = json!(images);
+        }
+    }
+
+    // Names of the images attached to a message.
+    pub fn images(item: &serde_json::Value) -> Vec<String> {
+        item["images"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|name| name.as_str().map(str::to_string))
+            .collect()
+    }
+
+    // Removes the conversation along with its attached images.
+    pub fn delete(file: &Path) -> Result<(), ConverseError> {
+        if let Some(history_path) = file.parent() {
+            let dir = Self::attachments_dir(history_path);
+            let conversation = Self::read(&file.to_path_buf());
+            for item in conversation["chat"].as_array().into_iter().flatten() {
+                for name in Self::images(item) {
+                    fs::remove_file(dir.join(name)).ok();
+                }
+            }
+        }
+        fs::remove_file(file)?;
+        Ok(())
     }
 
     pub fn read_all(dir_path: PathBuf) -> Vec<PathBuf> {
         fs::create_dir(&dir_path).ok();
         let mut dir_files = Vec::new();
         if let Ok(files) = fs::read_dir(dir_path) {
-            for file in files {
-                dir_files.push(file.expect("Error reading file").path())
+            for file in files.flatten() {
+                // Skips the attachments directory.
+                if file
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with("-history.json")
+                {
+                    dir_files.push(file.path())
+                }
             }
         }
         dir_files.sort();
@@ -1,9 +1,11 @@
-use std::{env::var, fs, path::PathBuf, process::exit};
+use std::{collections::HashMap, env::var, fs, path::PathBuf, process::exit};
 
-use clap::Parser;
-use serde::Deserialize;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use crate::{error::ConverseError, models::Usage};
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct Config {
@@ -13,6 +15,15 @@ pub struct Config {
     pub cohere: ConfigCohere,
     pub claude: ConfigClaude,
     pub openai: ConfigOpenAI,
+    pub ollama: ConfigOllama,
+    pub custom: Vec<ConfigCustom>,
+    pub pricing: HashMap<String, ConfigPrice>,
+    pub files: ConfigFiles,
+    pub tools: ConfigTools,
+    pub mcp_servers: Vec<ConfigMcpServer>,
+    // Where the config was read from, so approved shell patterns can be saved to it.
+    #[serde(skip)]
+    pub path: PathBuf,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -41,7 +52,17 @@ pub struct ConfigGemini {
     pub api: String,
     pub use_model: u32,
     pub model: String,
+    pub models: Vec<String>,
+    pub system_prompt: String,
     pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
+    // Blocking threshold by harm category, e.g. HARM_CATEGORY_HARASSMENT = "BLOCK_ONLY_HIGH".
+    pub safety_settings: HashMap<String, String>,
+    // Lets Gemini search Google and cite the pages it found. Gemini can't call
+    // functions while searching, so tools are turned off with it.
+    pub grounding: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -49,8 +70,17 @@ pub struct ConfigGemini {
 pub struct ConfigCohere {
     pub api: String,
     pub use_model: u32,
+    pub system_prompt: String,
     pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
+    // Only supported by the v1 API.
     pub web_search: bool,
+    // 1 for the v1 chat API, 2 for v2. v2 needs a model.
+    pub api_version: u32,
+    pub model: String,
+    pub models: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -58,10 +88,20 @@ pub struct ConfigCohere {
 pub struct ConfigClaude {
     pub api: String,
     pub use_model: u32,
+    pub system_prompt: String,
     pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
     pub max_tokens: u32,
     pub model: String,
+    pub models: Vec<String>,
     pub anthropic_version: String,
+    // Tokens Claude may spend thinking before it answers, at least 1024. 0 turns thinking off.
+    pub thinking_budget: u32,
+    // Caches the system prompt and the history before the new prompt, so later
+    // prompts in the conversation cost less to send.
+    pub prompt_caching: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -69,8 +109,216 @@ pub struct ConfigClaude {
 pub struct ConfigOpenAI {
     pub api: String,
     pub use_model: u32,
+    pub system_prompt: String,
+    pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
+    pub model: String,
+    pub models: Vec<String>,
+    // How long reasoning models think: "low", "medium" or "high". Left to the model when empty.
+    pub reasoning_effort: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigOllama {
+    pub host: String,
+    pub use_model: u32,
+    pub system_prompt: String,
+    pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
+    pub model: String,
+    pub models: Vec<String>,
+}
+
+// How often and how long to wait before sending a request again after a 429 or 5xx.
+// Backoff is capped at max_delay; a longer Retry-After from the provider fails the request instead.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigRetry {
+    pub max_attempts: u32,
+    pub initial_delay: f64,
+    pub max_delay: f64,
+}
+
+// Limits on the text files read when attaching a file or a directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigFiles {
+    // In KiB. Larger files are skipped.
+    pub max_file_size: u64,
+    // Read from a single directory.
+    pub max_files: usize,
+}
+
+// Local tools the model can call.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigTools {
+    pub enabled: Vec<String>,
+    // Directories the read_file tool can read from.
+    pub read_file_dirs: Vec<PathBuf>,
+    // Responses with tool calls in a single answer, before giving up.
+    pub max_rounds: u32,
+    pub shell: ConfigShell,
+}
+
+// The shell tool, which runs commands after the user approves them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigShell {
+    // Used when the model doesn't pick a directory, and to resolve relative ones.
+    pub working_dir: PathBuf,
+    // In seconds.
+    pub timeout: u64,
+    // In KiB, for stdout and stderr each.
+    pub max_output: u64,
+    // Commands matching these run without asking, in any directory. Extended by "Always allow".
+    pub allow: Vec<String>,
+}
+
+// A Model Context Protocol server, declared with `[[mcp_servers]]`. It is started with
+// `command` and offers its tools over stdio.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigMcpServer {
+    pub name: String,
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    // Seconds to wait for each response, including the tool calls.
+    pub timeout: u64,
+}
+
+// Sampling parameters, set in a provider's section or for a single conversation.
+// Parameters that are not set are left to the provider.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigGeneration {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_output_tokens: Option<u32>,
+    pub stop: Vec<String>,
+    pub seed: Option<i64>,
+}
+
+impl ConfigGeneration {
+    // Parameters set in `overrides` replace these ones.
+    pub fn merged(&self, overrides: &Self) -> Self {
+        Self {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_output_tokens: overrides.max_output_tokens.or(self.max_output_tokens),
+            stop: if overrides.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                overrides.stop.clone()
+            },
+            seed: overrides.seed.or(self.seed),
+        }
+    }
+}
+
+// An OpenAI compatible endpoint, declared with `[[custom]]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfigCustom {
+    pub name: String,
+    pub base_url: String,
+    pub api: String,
+    pub use_model: u32,
+    pub system_prompt: String,
     pub conversation_input: serde_json::Value,
+    pub retry: ConfigRetry,
+    #[serde(flatten)]
+    pub generation: ConfigGeneration,
     pub model: String,
+    pub models: Vec<String>,
+    pub headers: HashMap<String, String>,
+    pub reasoning_effort: String,
+}
+
+// US dollars per million tokens, keyed by "provider/model" or just "provider" in `[pricing]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigPrice {
+    pub input: f64,
+    pub output: f64,
+    // Default to Anthropic's rates, 1.25 and 0.1 times the input price.
+    pub cache_write: Option<f64>,
+    pub cache_read: Option<f64>,
+}
+
+impl ConfigPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let cache_write = self.cache_write.unwrap_or(self.input * 1.25);
+        let cache_read = self.cache_read.unwrap_or(self.input * 0.1);
+        (usage.input_tokens as f64 * self.input
+            + usage.cache_creation_tokens as f64 * cache_write
+            + usage.cache_read_tokens as f64 * cache_read
+            + usage.output_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+impl Default for ConfigRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: 1.0,
+            max_delay: 30.0,
+        }
+    }
+}
+
+impl Default for ConfigFiles {
+    fn default() -> Self {
+        Self {
+            max_file_size: 256,
+            max_files: 100,
+        }
+    }
+}
+
+impl Default for ConfigTools {
+    fn default() -> Self {
+        Self {
+            enabled: vec![
+                "current_time".to_string(),
+                "calculator".to_string(),
+                "read_file".to_string(),
+            ],
+            read_file_dirs: Vec::new(),
+            max_rounds: 10,
+            shell: ConfigShell::default(),
+        }
+    }
+}
+
+impl Default for ConfigShell {
+    fn default() -> Self {
+        Self {
+            working_dir: PathBuf::from(env!("HOME")),
+            timeout: 30,
+            max_output: 64,
+            allow: Vec::new(),
+        }
+    }
+}
+
+impl Default for ConfigMcpServer {
+    fn default() -> Self {
+        Self {
+            name: "MCP".to_string(),
+            command: String::new(),
+            args: Vec::new(),
+            env: HashMap::new(),
+            timeout: 60,
+        }
+    }
 }
 
 impl Default for Theming {
@@ -109,7 +357,13 @@ impl Default for ConfigGemini {
             },
             use_model: 2,
             model: "gemini-2.5-pro".to_string(),
+            models: Vec::new(),
+            system_prompt: String::new(),
             conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
+            safety_settings: HashMap::new(),
+            grounding: false,
         }
     }
 }
@@ -125,8 +379,14 @@ impl Default for ConfigCohere {
                 }
             },
             use_model: 1,
+            system_prompt: String::new(),
             conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
             web_search: false,
+            api_version: 1,
+            model: String::new(),
+            models: Vec::new(),
         }
     }
 }
@@ -142,10 +402,16 @@ impl Default for ConfigClaude {
                 }
             },
             use_model: 1,
+            system_prompt: String::new(),
             conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
             max_tokens: 1024,
             model: "claude-3-haiku-20240307".to_string(),
+            models: Vec::new(),
             anthropic_version: "2023-06-01".to_string(),
+            thinking_budget: 0,
+            prompt_caching: false,
         }
     }
 }
@@ -161,8 +427,47 @@ impl Default for ConfigOpenAI {
                 }
             },
             use_model: 1,
+            system_prompt: String::new(),
             conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
             model: "gpt-3.5-turbo".to_string(),
+            models: Vec::new(),
+            reasoning_effort: String::new(),
+        }
+    }
+}
+
+impl Default for ConfigOllama {
+    fn default() -> Self {
+        Self {
+            host: "http://localhost:11434".to_string(),
+            use_model: 0,
+            system_prompt: String::new(),
+            conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
+            model: String::new(),
+            models: Vec::new(),
+        }
+    }
+}
+
+impl Default for ConfigCustom {
+    fn default() -> Self {
+        Self {
+            name: "Custom".to_string(),
+            base_url: String::new(),
+            api: String::new(),
+            use_model: 1,
+            system_prompt: String::new(),
+            conversation_input: json!([]),
+            retry: ConfigRetry::default(),
+            generation: ConfigGeneration::default(),
+            model: String::new(),
+            models: Vec::new(),
+            headers: HashMap::new(),
+            reasoning_effort: String::new(),
         }
     }
 }
@@ -176,39 +481,95 @@ impl Default for Config {
             cohere: ConfigCohere::default(),
             claude: ConfigClaude::default(),
             openai: ConfigOpenAI::default(),
+            ollama: ConfigOllama::default(),
+            custom: Vec::new(),
+            pricing: HashMap::new(),
+            files: ConfigFiles::default(),
+            tools: ConfigTools::default(),
+            mcp_servers: Vec::new(),
+            path: PathBuf::new(),
         }
     }
 }
 
 #[derive(Parser)]
 #[command(version)]
-struct Args {
+pub struct Args {
     #[arg(short, long)]
     /// Specify config file path
     config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print the tokens used and their cost, grouped by model and month
+    Usage,
 }
 
 impl Config {
-    pub fn new() -> Config {
-        let args = Args::parse();
+    // A price for the exact model is preferred over one for the whole provider.
+    pub fn price(&self, provider: &str, model: &str) -> Option<&ConfigPrice> {
+        self.pricing
+            .get(&format!("{}/{}", provider, model))
+            .or_else(|| self.pricing.get(provider))
+    }
+
+    // Saves a pattern to `[tools.shell] allow`, keeping the rest of the file as it is.
+    pub fn add_shell_pattern(&self, pattern: &str) -> Result<(), ConverseError> {
+        let text = fs::read_to_string(&self.path).unwrap_or_default();
+        let mut document: toml_edit::Document = text
+            .parse()
+            .map_err(|error| ConverseError::Config(format!("{}", error)))?;
+        // Written as `[tools.shell]` rather than inline tables.
+        if document.get("tools").is_none() {
+            let mut tools = toml_edit::Table::new();
+            tools.set_implicit(true);
+            document["tools"] = toml_edit::Item::Table(tools);
+        }
+        if document["tools"].get("shell").is_none() {
+            document["tools"]["shell"] = toml_edit::table();
+        }
+        let allow = &mut document["tools"]["shell"]["allow"];
+        if allow.is_none() {
+            *allow = toml_edit::value(toml_edit::Array::new());
+        }
+        let Some(patterns) = allow.as_array_mut() else {
+            return Err(ConverseError::Config(
+                "tools.shell.allow needs to be a list.".to_string(),
+            ));
+        };
+        patterns.push(pattern);
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, document.to_string())?;
+        Ok(())
+    }
 
-        let toml_str = if let Some(path) = args.config {
-            fs::read_to_string(path).unwrap_or_else(|e| {
+    pub fn new(args: &Args) -> Config {
+        let path = args.config.clone().unwrap_or_else(|| {
+            PathBuf::from(format!("{}/.config/converse/config.toml", env!("HOME")))
+        });
+        let toml_str = if args.config.is_some() {
+            fs::read_to_string(&path).unwrap_or_else(|e| {
                 eprintln!("Error reading file: {}", e);
                 exit(1)
             })
         } else {
-            fs::read_to_string(format!("{}/.config/converse/config.toml", env!("HOME")))
-                .unwrap_or_else(|e| {
-                    eprintln!("Error reading file: {}; using default values.", e);
-                    String::new()
-                })
+            fs::read_to_string(&path).unwrap_or_else(|e| {
+                eprintln!("Error reading file: {}; using default values.", e);
+                String::new()
+            })
         };
 
-        let config_file: Config = toml::from_str(&toml_str).unwrap_or_else(|e| {
+        let mut config_file: Config = toml::from_str(&toml_str).unwrap_or_else(|e| {
             eprintln!("Error deserializing the file: {}", e);
             exit(1)
         });
+        config_file.path = path;
 
         if config_file.gemini.use_model != 0 && config_file.gemini.api.is_empty() {
             eprintln!("Please set gemini api key in config.toml");
@@ -222,7 +583,66 @@ impl Config {
         if config_file.openai.use_model != 0 && config_file.openai.api.is_empty() {
             eprintln!("Please set openai api key in config.toml");
         }
+        for custom in &config_file.custom {
+            if custom.use_model != 0 && custom.base_url.is_empty() {
+                eprintln!("Please set base_url for {} in config.toml", custom.name);
+            }
+        }
 
         config_file
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(input: u64, output: u64, cache_write: u64, cache_read: u64) -> Usage {
+        Usage {
+            input_tokens: input,
+            output_tokens: output,
+            cache_creation_tokens: cache_write,
+            cache_read_tokens: cache_read,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn prices_are_per_million_tokens() {
+        let price = ConfigPrice {
+            input: 3.0,
+            output: 15.0,
+            cache_write: None,
+            cache_read: None,
+        };
+        assert_close(price.cost(&usage(1_000_000, 0, 0, 0)), 3.0);
+        assert_close(price.cost(&usage(2000, 1000, 0, 0)), 0.021);
+        assert_close(price.cost(&Usage::default()), 0.0);
+    }
+
+    #[test]
+    fn cache_defaults_to_anthropic_rates() {
+        let price = ConfigPrice {
+            input: 3.0,
+            output: 15.0,
+            cache_write: None,
+            cache_read: None,
+        };
+        assert_close(price.cost(&usage(0, 0, 1_000_000, 0)), 3.75);
+        assert_close(price.cost(&usage(0, 0, 0, 1_000_000)), 0.3);
+    }
+
+    #[test]
+    fn cache_prices_can_be_set() {
+        let price = ConfigPrice {
+            input: 3.0,
+            output: 15.0,
+            cache_write: Some(6.0),
+            cache_read: Some(0.5),
+        };
+        assert_close(price.cost(&usage(1000, 1000, 1000, 1000)), 0.0245);
+    }
+}
@@ -0,0 +1,169 @@
+// Text files attached to a question as context.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::config::ConfigFiles;
+
+// A file or directory attached by the user. Directories are read recursively,
+// keeping the files whose name matches `pattern`, e.g. "*.rs, *.toml".
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileSource {
+    pub path: PathBuf,
+    pub pattern: String,
+}
+
+// The contents of a file at the time it was read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContextFile {
+    pub path: PathBuf,
+    pub text: String,
+}
+
+impl ContextFile {
+    // Delimited, so the model can tell the file apart from the question.
+    pub fn block(&self) -> String {
+        format!(
+            "<file path=\"{}\">\n{}\n</file>",
+            self.path.display(),
+            self.text.trim_end()
+        )
+    }
+}
+
+// Hidden, unreadable, binary and too large files are skipped.
+pub fn read(sources: &[FileSource], limits: &ConfigFiles) -> Vec<ContextFile> {
+    let mut files = Vec::new();
+    for source in sources {
+        if source.path.is_dir() {
+            // `max_files` counts the files of each directory separately.
+            let mut dir_files = Vec::new();
+            read_dir(&source.path, &source.pattern, limits, &mut dir_files);
+            files.extend(dir_files);
+        } else if let Some(file) = read_file(&source.path, limits) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+fn read_dir(dir: &Path, pattern: &str, limits: &ConfigFiles, files: &mut Vec<ContextFile>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if files.len() >= limits.max_files {
+            return;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Symlinked directories are not followed, as they may loop.
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            read_dir(&entry.path(), pattern, limits, files);
+        } else if matches(pattern, &name) {
+            files.extend(read_file(&entry.path(), limits));
+        }
+    }
+}
+
+fn read_file(path: &Path, limits: &ConfigFiles) -> Option<ContextFile> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() || metadata.len() > limits.max_file_size * 1024 {
+        return None;
+    }
+    Some(ContextFile {
+        path: path.to_path_buf(),
+        text: fs::read_to_string(path).ok()?,
+    })
+}
+
+// Comma separated globs. Empty matches everything.
+fn matches(pattern: &str, name: &str) -> bool {
+    let globs: Vec<&str> = pattern
+        .split(',')
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .collect();
+    globs.is_empty() || globs.iter().any(|glob| glob_match(glob, name))
+}
+
+// `*` and `?` match any characters. Only the last `*` ever needs backtracking to, which
+// keeps this O(glob * text) without recursing.
+pub fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    // The position after the last `*`, and where in the text it stopped matching.
+    let mut star = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                g += 1;
+                star = Some((g, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_g, star_t)) => {
+                    g = star_g;
+                    t = star_t + 1;
+                    star = Some((star_g, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("*.rs", ".rs"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert!(glob_match("m?in.*", "main.rs"));
+        assert!(!glob_match("?", ""));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(!glob_match("a*b*c", "a-c-b"));
+        assert!(glob_match("naïve*", "naïve.txt"));
+        assert!(glob_match("*a", "aaa"));
+        assert!(glob_match("a*", "a"));
+        assert!(!glob_match("a?", "a"));
+        assert!(glob_match("**b", "ab"));
+    }
+
+    #[test]
+    fn globs_match_long_text() {
+        let text = "y".repeat(100_000);
+        assert!(!glob_match("*x", &text));
+        assert!(glob_match("*y", &text));
+        assert!(glob_match(&format!("{}*", text), &text));
+    }
+
+    #[test]
+    fn globs_with_many_stars_stay_fast() {
+        let text = "a".repeat(5_000);
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*c", &text));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*", &text));
+        assert!(!glob_match(
+            &"*a".repeat(1_000).replacen('a', "b", 1),
+            &text
+        ));
+    }
+}
@@ -1,3 +1,6 @@
 pub mod cache;
 pub mod config;
+pub mod files;
 pub mod md2pango;
+pub mod models_cache;
+pub mod time;
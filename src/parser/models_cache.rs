@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConverseError;
+
+use super::time;
+
+// Lists older than this are fetched again at startup.
+const MAX_AGE: i64 = 24 * 60 * 60;
+
+// Models listed by each provider, and the ones whose last request failed.
+// Saved as `<history_path>-models.json`, next to the history directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelsCache {
+    pub providers: HashMap<String, ListedModels>,
+    // Keyed by provider, then model.
+    pub failed: HashMap<String, HashMap<String, String>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListedModels {
+    pub time: i64,
+    pub models: Vec<String>,
+    // Set when the list could not be fetched, in which case `models` is the previous list.
+    pub error: Option<String>,
+}
+
+// Why a model is flagged in the model selector.
+pub enum ModelFlag<'a> {
+    Failed(&'a str),
+    // Not in the provider's list, which usually means it was deprecated.
+    Unlisted,
+}
+
+impl ModelsCache {
+    fn path(history_path: &Path) -> PathBuf {
+        let name = history_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        history_path.with_file_name(format!("{}-models.json", name))
+    }
+
+    pub fn read(history_path: &Path) -> Self {
+        fs::read_to_string(Self::path(history_path))
+            .ok()
+            .and_then(|cache_file| serde_json::from_str(&cache_file).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write(&self, history_path: &Path) -> Result<(), ConverseError> {
+        fs::write(Self::path(history_path), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn is_stale(&self, provider: &str) -> bool {
+        self.providers
+            .get(provider)
+            .is_none_or(|listed| time::now() - listed.time > MAX_AGE)
+    }
+
+    // A failed fetch keeps the models from the last one that worked.
+    pub fn update(&mut self, provider: &str, models: Result<Vec<String>, ConverseError>) {
+        let listed = self.providers.entry(provider.to_string()).or_default();
+        listed.time = time::now();
+        match models {
+            Ok(models) => {
+                listed.models = models;
+                listed.error = None;
+            }
+            Err(error) => listed.error = Some(error.to_string()),
+        }
+    }
+
+    // Returns whether anything changed, so the cache is only written when needed.
+    pub fn set_failed(&mut self, provider: &str, model: &str, error: Option<String>) -> bool {
+        let failed = self.failed.entry(provider.to_string()).or_default();
+        match error {
+            Some(error) => failed.insert(model.to_string(), error.clone()) != Some(error),
+            None => failed.remove(model).is_some(),
+        }
+    }
+
+    pub fn listed(&self, provider: &str) -> &[String] {
+        self.providers
+            .get(provider)
+            .map(|listed| listed.models.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn flag(&self, provider: &str, model: &str) -> Option<ModelFlag<'_>> {
+        if let Some(error) = self
+            .failed
+            .get(provider)
+            .and_then(|failed| failed.get(model))
+        {
+            return Some(ModelFlag::Failed(error));
+        }
+        let listed = self.listed(provider);
+        (!listed.is_empty() && !listed.iter().any(|listed| listed == model))
+            .then_some(ModelFlag::Unlisted)
+    }
+}
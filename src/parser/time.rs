@@ -0,0 +1,132 @@
+// Dates without pulling in a full date/time library.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+// Used to name attached images, which may be saved several in a row.
+pub fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos())
+}
+
+// Days since 1970-01-01 for a date in the proleptic gregorian calendar.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The (year, month, day) of a number of days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Seconds since the unix epoch for a timestamp like `2024-05-01T12:00:30.5+02:00`.
+pub fn parse_rfc3339(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.trim().split_once(['T', 't', ' '])?;
+
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let year = date.next()?.ok()?;
+    let month = date.next()?.ok()?;
+    let day = date.next()?.ok()?;
+
+    let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(index) => time.split_at(index),
+        None => (time, ""),
+    };
+    let mut clock = clock.splitn(3, ':');
+    let hour = clock.next()?.parse::<i64>().ok()?;
+    let minute = clock.next()?.parse::<i64>().ok()?;
+    let second = clock.next()?.parse::<f64>().ok()? as i64;
+
+    let offset = match offset {
+        "" | "Z" | "z" => 0,
+        offset => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        }
+    };
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+// Seconds since the unix epoch for an HTTP date like `Wed, 21 Oct 2015 07:28:00 GMT`.
+pub fn parse_http_date(date: &str) -> Option<i64> {
+    let (_, date) = date.trim().split_once(", ")?;
+    let mut parts = date.split_whitespace();
+    let day = parts.next()?.parse::<i64>().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let mut clock = parts.next()?.splitn(3, ':').map(str::parse::<i64>);
+    let hour = clock.next()?.ok()?;
+    let minute = clock.next()?.ok()?;
+    let second = clock.next()?.ok()?;
+    if parts.next()? != "GMT" {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rfc3339_with_offsets() {
+        assert_eq!(parse_rfc3339("2015-10-21T07:28:00Z"), Some(1445412480));
+        assert_eq!(
+            parse_rfc3339("2015-10-21T09:28:00.5+02:00"),
+            Some(1445412480)
+        );
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(1445412480)
+        );
+        assert_eq!(parse_http_date("Wed, 21 Okt 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_http_date("120"), None);
+    }
+}
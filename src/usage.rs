@@ -0,0 +1,173 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    models::Usage,
+    parser::{cache::Cache, config::Config, time},
+};
+
+// An answer from the history that has its token usage recorded.
+pub struct Answer {
+    pub time: i64,
+    pub provider: String,
+    pub model: String,
+    pub usage: Usage,
+}
+
+impl Answer {
+    // None when `[pricing]` has no price for the model.
+    pub fn cost(&self, config: &Config) -> Option<f64> {
+        config
+            .price(&self.provider, &self.model)
+            .map(|price| price.cost(&self.usage))
+    }
+}
+
+// Answers saved before timestamps were added use the time the conversation was created.
+pub fn answer(chat: &serde_json::Value, provider: &str, created: i64) -> Option<Answer> {
+    let usage = serde_json::from_value(chat.get("usage")?.clone()).ok()?;
+    Some(Answer {
+        time: chat["time"].as_i64().unwrap_or(created),
+        provider: provider.to_string(),
+        model: chat["model"].as_str().unwrap_or_default().to_string(),
+        usage,
+    })
+}
+
+pub fn answers(conversation: &serde_json::Value, created: i64) -> Vec<Answer> {
+    let provider = Cache::selection(conversation).unwrap_or_default().provider;
+    conversation["chat"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|chat| answer(chat, &provider, created))
+        .collect()
+}
+
+// History files are named after the time they were created, in microseconds.
+fn history_answers(file: &PathBuf) -> Vec<Answer> {
+    let created = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix("-history.json"))
+        .and_then(|micros| micros.parse::<i64>().ok())
+        .map_or(0, |micros| micros / 1_000_000);
+    answers(&Cache::read(file), created)
+}
+
+// Cost of every answer in the history on a day, counted in days since the epoch (UTC).
+pub fn day_cost(config: &Config, day: i64) -> f64 {
+    Cache::read_all(config.general.history_path.clone())
+        .iter()
+        .flat_map(history_answers)
+        .filter(|answer| answer.time.div_euclid(86400) == day)
+        .filter_map(|answer| answer.cost(config))
+        .sum()
+}
+
+// Running cost of the current day, so the history is only read once at startup.
+#[derive(Default)]
+pub struct DayTotal {
+    day: i64,
+    cost: f64,
+}
+
+impl DayTotal {
+    // Adds the cost of an answer given at a time in seconds since the epoch, starting
+    // over on a new day. Returns the total of that day.
+    pub fn add(&mut self, time: i64, cost: f64) -> f64 {
+        let day = time.div_euclid(86400);
+        if day != self.day {
+            self.day = day;
+            self.cost = 0.0;
+        }
+        self.cost += cost;
+        self.cost
+    }
+}
+
+pub fn format_cost(cost: f64) -> String {
+    if cost > 0.0 && cost < 0.01 {
+        format!("${:.4}", cost)
+    } else {
+        format!("${:.2}", cost)
+    }
+}
+
+// Used by `converse usage`.
+pub fn print_report(config: &Config) {
+    let mut months: BTreeMap<String, BTreeMap<String, (Usage, Option<f64>)>> = BTreeMap::new();
+    for file in Cache::read_all(config.general.history_path.clone()) {
+        for answer in history_answers(&file) {
+            let (year, month, _) = time::civil_from_days(answer.time.div_euclid(86400));
+            let model = if answer.model.is_empty() {
+                answer.provider.clone()
+            } else {
+                format!("{}/{}", answer.provider, answer.model)
+            };
+            let (usage, cost) = months
+                .entry(format!("{}-{:02}", year, month))
+                .or_default()
+                .entry(model)
+                .or_default();
+            *usage += answer.usage;
+            if let Some(answer_cost) = answer.cost(config) {
+                *cost.get_or_insert(0.0) += answer_cost;
+            }
+        }
+    }
+
+    if months.is_empty() {
+        println!(
+            "No token usage recorded in {}.",
+            config.general.history_path.display()
+        );
+        return;
+    }
+
+    let width = months
+        .values()
+        .flat_map(|models| models.keys())
+        .map(|model| model.len())
+        .max()
+        .unwrap_or_default()
+        .max(5);
+    let mut unpriced = false;
+    for (month, models) in &months {
+        println!("{}", month);
+        println!(
+            "  {:<width$}  {:>12}  {:>12}  {:>12}  {:>10}",
+            "Model", "Input", "Cached", "Output", "Cost"
+        );
+        let mut month_cost = 0.0;
+        for (model, (usage, cost)) in models {
+            unpriced |= cost.is_none();
+            month_cost += cost.unwrap_or_default();
+            println!(
+                "  {:<width$}  {:>12}  {:>12}  {:>12}  {:>10}",
+                model,
+                usage.input_tokens + usage.cache_creation_tokens,
+                usage.cache_read_tokens,
+                usage.output_tokens,
+                cost.map_or("-".to_string(), format_cost)
+            );
+        }
+        println!("  {:<width$}  {:>52}\n", "Total", format_cost(month_cost));
+    }
+    if unpriced {
+        println!("Models without a price in [pricing] are shown with -.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_total_starts_over_on_a_new_day() {
+        let mut total = DayTotal::default();
+        let noon = 20_000 * 86400 + 43200;
+        assert_eq!(total.add(noon, 1.5), 1.5);
+        assert_eq!(total.add(noon + 3600, 0.25), 1.75);
+        assert_eq!(total.add(noon + 43200, 0.5), 0.5);
+    }
+}